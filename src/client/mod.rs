@@ -1,22 +1,26 @@
 //! HTTP Client
 
+use std::collections::HashMap;
 use std::fmt;
 use std::io;
 use std::sync::Arc;
 use std::time::Duration;
 
 use futures::{Async, Future, Poll};
-use futures::future::{self, Either, Executor};
+use futures::future::{self, Either, Executor, Loop};
 use futures::sync::oneshot;
-use http::{Method, Request, Response, Uri, Version};
+use futures_timer::Delay;
+use http::{Method, Request, Response, StatusCode, Uri, Version};
 use http::header::{Entry, HeaderValue, HOST};
 use http::uri::Scheme;
 
 use body::{Body, Payload};
 use common::Exec;
-use self::pool::{Pool, Poolable, Reservation};
+use headers;
+use self::pool::{Pool, PoolConfig, Poolable, Reservation};
 
 pub use self::connect::{Connect, HttpConnector};
+pub use self::pool::{DialCounts, IdleOrder, ReaperStats};
 
 use self::connect::Destination;
 
@@ -177,15 +181,43 @@ where C: Connect + Sync + 'static,
         let connect = {
             let executor = self.executor.clone();
             let pool = self.pool.clone();
+            let failed_pool = self.pool.clone();
+            let failed_key = pool_key.clone();
             let h1_writev = self.h1_writev;
             let connector = self.connector.clone();
             let dst = Destination {
                 uri: url,
             };
             future::lazy(move || {
-                if let Some(connecting) = pool.connecting(&pool_key) {
-                    Either::A(connector.connect(dst)
-                        .map_err(::Error::new_connect)
+                let dial_pool = pool.clone();
+                let dial_key = pool_key.clone();
+                // After connect failures, don't dial again until the pool's
+                // backoff for this key is over. And an HTTP/2 dial already in
+                // progress is waited on by the checkout instead, unless it
+                // outlasts the pool's coalesce window, after which this may
+                // dial too. Likewise while every HTTP/2 connection for the key
+                // has all of its streams in use, the checkout waits for one.
+                future::loop_fn((), move |()| {
+                    if let Some(connecting) = dial_pool.connecting(&dial_key) {
+                        return Either::A(future::ok(Loop::Break(connecting)));
+                    }
+                    let retry_at = dial_pool.dial_allowed_at(&dial_key)
+                        .or_else(|| dial_pool.coalesce_window_ends_at(&dial_key));
+                    match retry_at {
+                        Some(at) => Either::B(Either::A(Delay::new_at(at).then(|_| {
+                            Ok::<_, ::Error>(Loop::Continue(()))
+                        }))),
+                        None => {
+                            let canceled = ::Error::new_canceled(Some("waiting on an HTTP/2 connection"));
+                            Either::B(Either::B(future::err(canceled)))
+                        }
+                    }
+                }).and_then(move |connecting| {
+                    connector.connect(dst)
+                        .map_err(move |err| {
+                            failed_pool.connect_failed(&failed_key);
+                            ::Error::new_connect(err)
+                        })
                         .and_then(move |(io, connected)| {
                             conn::Builder::new()
                                 .h1_writev(h1_writev)
@@ -209,31 +241,34 @@ where C: Connect + Sync + 'static,
                                         },
                                     })
                                 })
-                        }))
-                } else {
-                    let canceled = ::Error::new_canceled(Some("HTTP/2 connection in progress"));
-                    Either::B(future::err(canceled))
-                }
+                        })
+                })
             })
         };
 
-        let race = checkout.select(connect)
-            .map(|(pooled, _work)| pooled)
-            .or_else(|(e, other)| {
-                // Either checkout or connect could get canceled:
-                //
-                // 1. Connect is canceled if this is HTTP/2 and there is
-                //    an outstanding HTTP/2 connecting task.
-                // 2. Checkout is canceled if the pool cannot deliver an
-                //    idle connection reliably.
-                //
-                // In both cases, we should just wait for the other future.
-                if e.is_canceled() {
-                    //trace!("checkout/connect race canceled: {}", e);
-                    Either::A(other.map_err(ClientError::Normal))
-                } else {
-                    Either::B(future::err(ClientError::Normal(e)))
-                }
+        // Dialing waits for the checkout rate limit too, otherwise a
+        // throttled checkout would still open a new connection.
+        let race = checkout.started()
+            .map_err(|e| ClientError::Normal(::Error::from(e)))
+            .and_then(move |checkout| {
+                checkout.map_err(::Error::from).select(connect)
+                    .map(|(pooled, _work)| pooled)
+                    .or_else(|(e, other)| {
+                        // Either checkout or connect could get canceled:
+                        //
+                        // 1. Connect is canceled if this is HTTP/2 and there is
+                        //    an outstanding HTTP/2 connecting task.
+                        // 2. Checkout is canceled if the pool cannot deliver an
+                        //    idle connection reliably.
+                        //
+                        // In both cases, we should just wait for the other future.
+                        if e.is_canceled() {
+                            //trace!("checkout/connect race canceled: {}", e);
+                            Either::A(other.map_err(ClientError::Normal))
+                        } else {
+                            Either::B(future::err(ClientError::Normal(e)))
+                        }
+                    })
             });
 
         let executor = self.executor.clone();
@@ -242,6 +277,7 @@ where C: Connect + Sync + 'static,
             if ver == Ver::Http1 {
                 set_relative_uri(req.uri_mut(), pooled.is_proxied);
             }
+            pooled.set_last_idempotent(req.method().is_idempotent());
             let fut = pooled.send_request_retryable(req)
                 .map_err(move |(err, orig_req)| {
                     if let Some(req) = orig_req {
@@ -255,6 +291,21 @@ where C: Connect + Sync + 'static,
                     }
                 })
                 .and_then(move |mut res| {
+                    if ver == Ver::Http1 {
+                        if let Some(timeout) = headers::keep_alive_timeout(res.headers()) {
+                            pooled.set_idle_timeout(Some(timeout));
+                        }
+                    }
+                    // A 5xx counts against the connection, and a 503 may
+                    // mean the server is briefly overloaded, so the
+                    // connection isn't reused right away.
+                    if res.status().is_server_error() {
+                        pooled.record_error();
+                        if res.status() == StatusCode::SERVICE_UNAVAILABLE {
+                            pooled.quarantine();
+                        }
+                    }
+
                     // If pooled is HTTP/2, we can toss this reference immediately.
                     //
                     // when pooled is dropped, it will try to insert back into the
@@ -291,6 +342,40 @@ where C: Connect + Sync + 'static,
         Box::new(resp)
     }
 
+    /// Get a snapshot of what this Client's connection pool is doing.
+    ///
+    /// Clones of a Client share the same pool.
+    pub fn pool_stats(&self) -> PoolStats {
+        let stats = self.pool.stats();
+        // A Client only uses one HTTP version, so each host has one key.
+        PoolStats {
+            closed_rejections_by_host: stats.closed_rejections.into_iter()
+                .map(|(key, count)| ((*key.0).clone(), count))
+                .collect(),
+            connecting: stats.connecting,
+            dials_coalesced: stats.dials_coalesced,
+            dials_in_flight: stats.dials_in_flight.into_iter()
+                .map(|(key, counts)| ((*key.0).clone(), counts))
+                .collect(),
+            dials_started: stats.dials_started,
+            idle_by_host: stats.idle_by_host,
+            idle_connections: stats.idle_connections,
+            parked_entries_died: stats.parked_entries_died,
+            parked_waiters: stats.parked_waiters,
+            reaper: stats.reaper,
+        }
+    }
+
+    /// Render what this Client's connection pool is doing in the Prometheus
+    /// text exposition format, with each metric name starting with
+    /// `prefix`.
+    ///
+    /// Metrics for each host only list the busiest hosts on their own, see
+    /// `Builder::pool_metrics_top_keys`, and sum the rest.
+    pub fn pool_metrics_text(&self, prefix: &str) -> String {
+        self.pool.metrics_text(prefix)
+    }
+
     fn schedule_pool_timer(&self) {
         self.pool.spawn_expired_interval(&self.executor);
     }
@@ -317,6 +402,83 @@ impl<C, B> fmt::Debug for Client<C, B> {
     }
 }
 
+/// A snapshot of a Client's connection pool, from `Client::pool_stats`.
+#[derive(Clone, Debug)]
+pub struct PoolStats {
+    closed_rejections_by_host: HashMap<String, u64>,
+    connecting: usize,
+    dials_coalesced: u64,
+    dials_in_flight: HashMap<String, DialCounts>,
+    dials_started: u64,
+    idle_by_host: HashMap<String, usize>,
+    idle_connections: usize,
+    parked_entries_died: u64,
+    parked_waiters: usize,
+    reaper: Option<ReaperStats>,
+}
+
+impl PoolStats {
+    /// The number of idle connections to each host that were found to be
+    /// closed when a request tried to use them.
+    ///
+    /// A high count for one host suggests its server closes idle
+    /// connections sooner than `Builder::keep_alive_timeout`.
+    pub fn closed_rejections_by_host(&self) -> &HashMap<String, u64> {
+        &self.closed_rejections_by_host
+    }
+
+    /// The number of HTTP/2 connections being established.
+    pub fn connecting(&self) -> usize {
+        self.connecting
+    }
+
+    /// The number of HTTP/2 dials that waited on one already in progress,
+    /// instead of starting their own.
+    pub fn dials_coalesced(&self) -> u64 {
+        self.dials_coalesced
+    }
+
+    /// For each host dialed so far, how many dials are in progress, and
+    /// the most that ever were at once.
+    pub fn dials_in_flight(&self) -> &HashMap<String, DialCounts> {
+        &self.dials_in_flight
+    }
+
+    /// The number of HTTP/2 dials that were started.
+    pub fn dials_started(&self) -> u64 {
+        self.dials_started
+    }
+
+    /// The number of idle connections for each host, whatever their
+    /// version.
+    pub fn idle_by_host(&self) -> &HashMap<String, usize> {
+        &self.idle_by_host
+    }
+
+    /// The number of idle connections, including quarantined ones.
+    pub fn idle_connections(&self) -> usize {
+        self.idle_connections
+    }
+
+    /// The number of connections returned to the pool for a waiting
+    /// request that were found to be closed on arrival.
+    pub fn parked_entries_died(&self) -> u64 {
+        self.parked_entries_died
+    }
+
+    /// The number of requests waiting for an idle connection. Some may
+    /// have been canceled and not cleaned up yet.
+    pub fn parked_waiters(&self) -> usize {
+        self.parked_waiters
+    }
+
+    /// What the background cleanup of expired idle connections has done,
+    /// if it has started.
+    pub fn reaper(&self) -> Option<&ReaperStats> {
+        self.reaper.as_ref()
+    }
+}
+
 /// A `Future` that will resolve to an HTTP Response.
 #[must_use = "futures do nothing unless polled"]
 pub struct FutureResponse(Box<Future<Item=Response<Body>, Error=::Error> + Send + 'static>);
@@ -493,8 +655,7 @@ pub struct Builder {
     keep_alive: bool,
     keep_alive_timeout: Option<Duration>,
     h1_writev: bool,
-    //TODO: make use of max_idle config
-    max_idle: usize,
+    pool: PoolConfig,
     retry_canceled_requests: bool,
     set_host: bool,
     ver: Ver,
@@ -507,7 +668,7 @@ impl Default for Builder {
             keep_alive: true,
             keep_alive_timeout: Some(Duration::from_secs(90)),
             h1_writev: true,
-            max_idle: 5,
+            pool: PoolConfig::new(),
             retry_canceled_requests: true,
             set_host: true,
             ver: Ver::Http1,
@@ -536,6 +697,185 @@ impl Builder {
         self
     }
 
+    /// Set the maximum number of idle connections kept for each host.
+    ///
+    /// Once a host has this many, connections that become idle are closed
+    /// instead of kept. Pass `None` for no limit.
+    ///
+    /// Default is no limit.
+    #[inline]
+    pub fn max_idle_per_host(&mut self, max: Option<usize>) -> &mut Self {
+        self.pool.max_idle_per_host(max);
+        self
+    }
+
+    /// Set the maximum number of requests that may wait for an idle
+    /// connection to each host.
+    ///
+    /// Beyond this, a request doesn't wait for a connection to be returned
+    /// to the pool, and uses the one it dials. Pass `None` for no limit.
+    ///
+    /// Default is no limit.
+    #[inline]
+    pub fn max_parked_per_host(&mut self, max: Option<usize>) -> &mut Self {
+        self.pool.max_parked_per_host(max);
+        self
+    }
+
+    /// Set how long a request waiting for an idle connection goes before
+    /// checking the pool again on its own, in case it missed one.
+    ///
+    /// Pass `None` to only check when a connection is returned.
+    ///
+    /// Default is `None`.
+    #[inline]
+    pub fn max_park_duration(&mut self, dur: Option<Duration>) -> &mut Self {
+        self.pool.max_park_duration(dur);
+        self
+    }
+
+    /// Set how often expired idle connections are cleaned up, instead of
+    /// once every `keep_alive_timeout`.
+    ///
+    /// Nothing is cleaned up in the background without a
+    /// `keep_alive_timeout`.
+    ///
+    /// Default is `None`, cleaning up every `keep_alive_timeout`.
+    #[inline]
+    pub fn pool_reap_interval(&mut self, every: Option<Duration>) -> &mut Self {
+        self.pool.reap_interval(every);
+        self
+    }
+
+    /// Configure the backoff before dialing a host again after connecting
+    /// to it failed.
+    ///
+    /// After each consecutive failure, the delay doubles, starting at
+    /// `base` and up to `max`, with some jitter. If `fail_fast` is true, a
+    /// request that finds no idle connection during the backoff fails
+    /// instead of waiting for it to end.
+    ///
+    /// Default is a base of 100 milliseconds, a max of 10 seconds, and
+    /// waiting.
+    #[inline]
+    pub fn dial_backoff(&mut self, base: Duration, max: Duration, fail_fast: bool) -> &mut Self {
+        self.pool.dial_backoff(base, max, fail_fast);
+        self
+    }
+
+    /// Limit how many requests per second may start for each host, allowing
+    /// bursts of up to `burst` at once. Pass `None` for no limit.
+    ///
+    /// A request over the limit waits, even if there are idle connections.
+    ///
+    /// Default is no limit.
+    #[inline]
+    pub fn checkout_rate_limit(&mut self, per_sec: Option<u32>, burst: u32) -> &mut Self {
+        self.pool.checkout_rate_limit(per_sec, burst);
+        self
+    }
+
+    /// Set which idle connection to a host a request uses.
+    ///
+    /// Default is `IdleOrder::Lifo`.
+    #[inline]
+    pub fn pool_idle_order(&mut self, order: IdleOrder) -> &mut Self {
+        self.pool.idle_order(order);
+        self
+    }
+
+    /// Set whether a request that finds only expired idle connections may
+    /// still use one that isn't known to be closed, instead of dialing.
+    ///
+    /// The server may have already closed its end of such a connection.
+    ///
+    /// Default is `false`.
+    #[inline]
+    pub fn pool_reuse_expired(&mut self, enabled: bool) -> &mut Self {
+        self.pool.reuse_expired(enabled);
+        self
+    }
+
+    /// Set whether a request keeps looking through a host's idle
+    /// connections after finding a closed one.
+    ///
+    /// The closed connection is removed either way. When `false`, the
+    /// request dials instead of looking further, which is cheaper when most
+    /// of the connections are closed.
+    ///
+    /// Default is `true`.
+    #[inline]
+    pub fn pool_scan_past_closed(&mut self, enabled: bool) -> &mut Self {
+        self.pool.scan_past_closed(enabled);
+        self
+    }
+
+    /// Set whether every closed or expired connection removed from the pool
+    /// is traced, instead of about one summary per host per second.
+    ///
+    /// Default is `false`.
+    #[inline]
+    pub fn pool_trace_each_removal(&mut self, enabled: bool) -> &mut Self {
+        self.pool.trace_each_removal(enabled);
+        self
+    }
+
+    /// Set how long an HTTP/1 connection that was sent a
+    /// `503 Service Unavailable` waits before it is reused.
+    ///
+    /// Default is 1 second.
+    #[inline]
+    pub fn pool_quarantine_duration(&mut self, dur: Duration) -> &mut Self {
+        self.pool.quarantine_duration(dur);
+        self
+    }
+
+    /// Set how long an HTTP/1 connection waits before it is reused, if its
+    /// last request wasn't idempotent, such as a `POST`.
+    ///
+    /// Pass `None` to reuse such connections right away.
+    ///
+    /// Default is `None`.
+    #[inline]
+    pub fn non_idempotent_cooldown(&mut self, dur: Option<Duration>) -> &mut Self {
+        self.pool.non_idempotent_cooldown(dur);
+        self
+    }
+
+    /// Set how many `5xx` responses an HTTP/1 connection may receive before
+    /// it is closed instead of reused. Pass `None` to never close one for
+    /// this.
+    ///
+    /// Default is `None`.
+    #[inline]
+    pub fn pool_error_threshold(&mut self, max: Option<u64>) -> &mut Self {
+        self.pool.error_threshold(max);
+        self
+    }
+
+    /// Set how long a connection may be in use before a warning is logged
+    /// that it may have been leaked. Pass `None` to never warn.
+    ///
+    /// This is checked while cleaning up idle connections, so it only
+    /// works with a `keep_alive_timeout`.
+    ///
+    /// Default is `None`.
+    #[inline]
+    pub fn pool_leak_warning(&mut self, after: Option<Duration>) -> &mut Self {
+        self.pool.leak_warning(after);
+        self
+    }
+
+    /// Set how many hosts `Client::pool_metrics_text` lists on their own,
+    /// with the rest summed together.
+    ///
+    /// Default is 10.
+    #[inline]
+    pub fn pool_metrics_top_keys(&mut self, max: usize) -> &mut Self {
+        self.pool.metrics_top_keys(max);
+        self
+    }
+
     /// Set whether HTTP/1 connections should try to use vectored writes,
     /// or always flatten into a single buffer.
     ///
@@ -564,6 +904,45 @@ impl Builder {
         self
     }
 
+    /// Set the most requests an HTTP/2 connection is given at once.
+    ///
+    /// Once every connection to a host has this many, requests wait for
+    /// one to finish instead of dialing. When a host has more than one
+    /// connection, requests go to the least busy one. Pass `None` for no
+    /// limit.
+    ///
+    /// Default is no limit.
+    #[inline]
+    pub fn http2_max_streams(&mut self, max: Option<usize>) -> &mut Self {
+        self.pool.http2_max_streams(max);
+        self
+    }
+
+    /// Set whether a new HTTP/2 connection is only shared with other
+    /// requests once its first request has finished.
+    ///
+    /// Until then, other requests to the same host wait for it, instead of
+    /// being given a connection that isn't yet known to work.
+    ///
+    /// Default is `false`.
+    #[inline]
+    pub fn http2_defer_pooling(&mut self, defer: bool) -> &mut Self {
+        self.pool.defer_http2_pooling(defer);
+        self
+    }
+
+    /// Set how long a request waits for another request's HTTP/2 connection
+    /// to the same host to be established, before dialing its own.
+    ///
+    /// Pass `None` to always wait.
+    ///
+    /// Default is `None`.
+    #[inline]
+    pub fn http2_connect_coalesce_window(&mut self, window: Option<Duration>) -> &mut Self {
+        self.pool.connect_coalesce_window(window);
+        self
+    }
+
     /// Set whether to retry requests that get disrupted before ever starting
     /// to write.
     ///
@@ -624,11 +1003,15 @@ impl Builder {
         B: Payload + Send,
         B::Data: Send,
     {
+        let mut pool = self.pool.clone();
+        pool
+            .enabled(self.keep_alive)
+            .timeout(self.keep_alive_timeout);
         Client {
             connector: Arc::new(connector),
             executor: self.exec.clone(),
             h1_writev: self.h1_writev,
-            pool: Pool::new(self.keep_alive, self.keep_alive_timeout),
+            pool: Pool::with_config(pool),
             retry_canceled_requests: self.retry_canceled_requests,
             set_host: self.set_host,
             ver: self.ver,
@@ -642,7 +1025,7 @@ impl fmt::Debug for Builder {
             .field("keep_alive", &self.keep_alive)
             .field("keep_alive_timeout", &self.keep_alive_timeout)
             .field("http1_writev", &self.h1_writev)
-            .field("max_idle", &self.max_idle)
+            .field("pool", &self.pool)
             .field("set_host", &self.set_host)
             .field("version", &self.ver)
             .finish()
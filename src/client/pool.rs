@@ -1,12 +1,26 @@
+//! The connection pool used by `Client`.
+//!
+//! Everything here is crate-internal, except for the few types `client`
+//! re-exports. Outside of hyper, each `PoolConfig` setting is set through
+//! the `client::Builder` method of a similar name, and the pool is observed
+//! through `Client::pool_stats` and `Client::pool_metrics_text`. What `Pooled` records about each connection comes
+//! from the requests `Client` sends on it.
+
+use std::cmp;
+use std::cell::Cell;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::error::Error as StdError;
 use std::fmt;
+use std::hash::{BuildHasher, Hasher};
 use std::ops::{Deref, DerefMut};
 use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use std::time::{Duration, Instant};
 
 use futures::{Future, Async, Poll, Stream};
 use futures::sync::oneshot;
-use futures_timer::Interval;
+use futures_timer::{Delay, Interval};
 
 use common::{Exec, Never};
 use super::Ver;
@@ -37,6 +51,12 @@ pub(super) enum Reservation<T> {
     /// This connection could be used multiple times, the first one will be
     /// reinserted into the `idle` pool, and the second will be given to
     /// the `Checkout`.
+    ///
+    /// Both values must be handles to the *same* connection, not copies of
+    /// it. The pool never clones a `T` itself, so it has no way of knowing
+    /// when the last handle is gone; the connection is expected to notice
+    /// that on its own (for HTTP/2, the connection task finishes once every
+    /// `SendRequest` sender has been dropped).
     #[allow(unused)]
     Shared(T, T),
     /// This connection requires unique access. It will be returned after
@@ -47,15 +67,70 @@ pub(super) enum Reservation<T> {
 /// Simple type alias in case the key type needs to be adjusted.
 type Key = (Arc<String>, Ver);
 
+type Random = FnMut() -> u64 + Send;
+
+/// Why an idle connection was removed from the pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EvictReason {
+    /// The connection was closed.
+    Closed,
+    /// The connection was idle longer than the pool's timeout.
+    Expired,
+}
+
+/// Something the pool did, recorded so tests can assert the exact order
+/// things happened in.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+enum PoolEvent {
+    /// A connection was given back to the pool.
+    Put(Key),
+    /// An idle connection was handed to a checkout.
+    Take(Key),
+    /// A checkout started waiting for a connection.
+    Park(Key),
+    /// A connection that was put back went to a parked checkout.
+    CompleteParked(Key),
+    /// An idle connection was removed, including when it expired.
+    Evict(Key, EvictReason),
+}
+
 struct PoolInner<T> {
+    // A decaying count of how often each key's connections were recently
+    // taken or put back.
+    activity: HashMap<Key, Activity>,
+    // Keys whose recent connect attempts failed, and when the next dial
+    // is allowed to start.
+    backoff: HashMap<Key, Backoff>,
+    // When each checked out connection was handed out, keyed by
+    // `Pooled::id`.
+    busy: HashMap<usize, (Key, Instant)>,
+    // Ids in `busy` the idle interval already warned about.
+    busy_warned: HashSet<usize>,
+    // How many idle connections each key's checkouts found already closed.
+    closed_rejections: HashMap<Key, u64>,
+    config: PoolConfig,
     // A flag that a connection is being estabilished, and the connection
     // should be shared. This prevents making multiple HTTP/2 connections
     // to the same host.
-    connecting: HashSet<Key>,
-    enabled: bool,
+    connecting: HashMap<Key, Dialing>,
+    // The tokens each key has for starting checkouts, when rate limited.
+    checkout_tokens: HashMap<Key, TokenBucket>,
+    // How many HTTP/2 dials were started, versus how many were able to
+    // wait on an existing dial instead.
+    dials_coalesced: u64,
+    // Dials in flight for each key, of either version.
+    dials_in_flight: HashMap<Key, DialCounts>,
+    dials_started: u64,
+    // Everything the pool did, for tests to check.
+    #[cfg(test)]
+    events: Vec<PoolEvent>,
     // These are internal Conns sitting in the event loop in the KeepAlive
     // state, waiting to receive a new Request to send on the socket.
     idle: HashMap<Key, Vec<Idle<T>>>,
+    // The keys `Pool::metrics_text` lists on their own, in the order they
+    // were picked.
+    metrics_keys: Vec<Key>,
     // These are outstanding Checkouts that are waiting for a socket to be
     // able to send a Request one. This is used when "racing" for a new
     // connection.
@@ -65,118 +140,731 @@ struct PoolInner<T> {
     // this list is checked for any parked Checkouts, and tries to notify
     // them that the Conn could be used instead of waiting for a brand new
     // connection.
-    parked: HashMap<Key, VecDeque<oneshot::Sender<T>>>,
-    timeout: Option<Duration>,
+    //
+    // Each waiter has an id from `next_park_id`, so a Checkout can find
+    // itself in the queue.
+    parked: HashMap<Key, VecDeque<(usize, oneshot::Sender<Option<(T, Meta)>>)>>,
+    // How many connections sent to parked Checkouts were already closed.
+    parked_entries_died: u64,
+    next_park_id: usize,
+    // The source of randomness for `IdleOrder::WeightedRandom`, if not the
+    // default.
+    random: Option<Box<Random>>,
+    // What the idle interval has done so far.
+    reaper: ReaperStats,
+    // The streams checked out on each HTTP/2 connection, by `Meta::conn_id`,
+    // when `PoolConfig::http2_max_streams` is set.
+    streams: HashMap<u64, Streams>,
+    // Connections removed by `take` that haven't been logged yet.
+    removed_log: HashMap<Key, RemovedLog>,
     // A oneshot channel is used to allow the interval to be notified when
     // the Pool completely drops. That way, the interval can cancel immediately.
     idle_interval_ref: Option<oneshot::Sender<Never>>,
 }
 
+/// The tunables of a `Pool`, set before it is created.
+#[derive(Clone, Debug)]
+pub(super) struct PoolConfig {
+    backoff: BackoffConfig,
+    // How long other checkouts wait on an HTTP/2 dial before they may
+    // start one of their own. `None` means they always wait.
+    connect_coalesce_window: Option<Duration>,
+    // Whether new HTTP/2 connections wait until their first request is
+    // done before being pooled.
+    defer_http2_pooling: bool,
+    enabled: bool,
+    // How many errors a connection may record before it isn't pooled again.
+    error_threshold: Option<u64>,
+    // How many streams each HTTP/2 connection can have at once, if they
+    // are counted.
+    http2_max_streams: Option<usize>,
+    idle_order: IdleOrder,
+    // If a connection is checked out this long, the idle interval warns
+    // that it may have been leaked.
+    leak_warning: Option<Duration>,
+    // How many keys `Pool::metrics_text` reports per-key metrics for.
+    metrics_top_keys: usize,
+    // The most Checkouts that can be parked for a single key. Beyond this,
+    // checkouts fail instead of growing `parked`.
+    max_parked_per_host: Option<usize>,
+    // If a Checkout has been parked this long, it tries to `take` again,
+    // in case a connection became idle without notifying it.
+    max_park_duration: Option<Duration>,
+    // How long a quarantined connection sits in `idle` before `take` will
+    // consider handing it out again.
+    quarantine_duration: Duration,
+    // How long an HTTP/1 connection whose last request wasn't idempotent
+    // sits in `idle` before `take` will hand it out.
+    non_idempotent_cooldown: Option<Duration>,
+    // How often the idle interval runs, instead of every `timeout`.
+    reap_interval: Option<Duration>,
+    // Whether `take` hands out an expired connection that isn't closed,
+    // if there is nothing better.
+    reuse_expired: bool,
+    // Whether `take` keeps looking after finding a closed connection, or
+    // gives up and leaves the rest of the list for later.
+    scan_past_closed: bool,
+    timeout: Option<Duration>,
+    // Whether `take` logs every connection it removes, instead of a
+    // summary per key.
+    trace_each_removal: bool,
+    // The most idle connections per key. Beyond this, connections that
+    // become idle are closed instead of pooled.
+    max_idle_per_host: Option<usize>,
+    // How fast checkouts for each key may proceed, unless the key has its
+    // own limit.
+    checkout_rate: Option<RateLimit>,
+}
+
+impl PoolConfig {
+    pub(super) fn new() -> PoolConfig {
+        PoolConfig {
+            backoff: BackoffConfig {
+                base: Duration::from_millis(100),
+                max: Duration::from_secs(10),
+                fail_fast: false,
+            },
+            connect_coalesce_window: None,
+            defer_http2_pooling: false,
+            enabled: true,
+            error_threshold: None,
+            http2_max_streams: None,
+            idle_order: IdleOrder::Lifo,
+            leak_warning: None,
+            metrics_top_keys: 10,
+            max_parked_per_host: None,
+            max_park_duration: None,
+            quarantine_duration: Duration::from_secs(1),
+            non_idempotent_cooldown: None,
+            reap_interval: None,
+            reuse_expired: false,
+            scan_past_closed: true,
+            timeout: None,
+            trace_each_removal: false,
+            max_idle_per_host: None,
+            checkout_rate: None,
+        }
+    }
+
+    /// Set whether connections are pooled at all. Defaults to `true`.
+    pub(super) fn enabled(&mut self, enabled: bool) -> &mut PoolConfig {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Set how often the idle interval removes expired connections. `None`
+    /// means every `timeout`, the default.
+    ///
+    /// The interval doesn't run at all without a `timeout`.
+    pub(super) fn reap_interval(&mut self, every: Option<Duration>) -> &mut PoolConfig {
+        self.reap_interval = every;
+        self
+    }
+
+    /// Set how long a connection may sit idle before it is no longer
+    /// reused. `None` means forever, which is the default.
+    pub(super) fn timeout(&mut self, timeout: Option<Duration>) -> &mut PoolConfig {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Configure the backoff applied to dials after connect failures.
+    ///
+    /// After each consecutive failure for a key, the delay before the next
+    /// dial doubles (starting at `base`, up to `max`), with some jitter.
+    /// If `fail_fast` is true, checkouts that find no idle connection
+    /// during the backoff window return an error instead of waiting.
+    pub(super) fn dial_backoff(&mut self, base: Duration, max: Duration, fail_fast: bool) -> &mut PoolConfig {
+        self.backoff = BackoffConfig {
+            base,
+            max,
+            fail_fast,
+        };
+        self
+    }
+
+    /// Set the most checkouts that may wait for an idle connection for any
+    /// one key.
+    ///
+    /// Once full, new checkouts for that key fail with a canceled error
+    /// instead of waiting. Pass `None` for no limit, which is the default.
+    pub(super) fn max_parked_per_host(&mut self, max: Option<usize>) -> &mut PoolConfig {
+        self.max_parked_per_host = max;
+        self
+    }
+
+    /// Set whether a new HTTP/2 connection is only pooled once its first
+    /// `Pooled` handle is dropped, instead of immediately.
+    ///
+    /// This keeps other checkouts from using a connection before it is
+    /// known to work. Until then, other HTTP/2 dials for the same key wait
+    /// on this connection, just like while it was connecting.
+    pub(super) fn defer_http2_pooling(&mut self, defer: bool) -> &mut PoolConfig {
+        self.defer_http2_pooling = defer;
+        self
+    }
+
+    /// Set how long an HTTP/2 dial can be in progress before another one
+    /// is allowed for the same key.
+    ///
+    /// By default, there is only ever one HTTP/2 dial per key, no matter
+    /// how slow it is.
+    pub(super) fn connect_coalesce_window(&mut self, window: Option<Duration>) -> &mut PoolConfig {
+        self.connect_coalesce_window = window;
+        self
+    }
+
+    /// Set how long a parked checkout waits before checking the idle list
+    /// again on its own.
+    ///
+    /// This doesn't fail the checkout, it only re-parks it if there is
+    /// still no idle connection.
+    pub(super) fn max_park_duration(&mut self, dur: Option<Duration>) -> &mut PoolConfig {
+        self.max_park_duration = dur;
+        self
+    }
+
+    /// Set whether a checkout keeps searching an idle list after finding a
+    /// closed connection.
+    ///
+    /// The closed connection is always removed. If `false`, the checkout
+    /// doesn't look any further, which is cheaper when most of a list is
+    /// closed; the remaining entries are cleaned up by later checkouts or
+    /// the idle interval. Defaults to `true`.
+    ///
+    /// A `Checkout` that stops early doesn't wait for a connection to be
+    /// returned either, since it would be passing up idle ones. It fails
+    /// with `CheckoutError::Canceled`, so the `Client` goes on with its dial.
+    pub(super) fn scan_past_closed(&mut self, enabled: bool) -> &mut PoolConfig {
+        self.scan_past_closed = enabled;
+        self
+    }
+
+    /// Set how long a connection returned with `Pooled::quarantine` must
+    /// wait before being reused. Defaults to 1 second.
+    pub(super) fn quarantine_duration(&mut self, dur: Duration) -> &mut PoolConfig {
+        self.quarantine_duration = dur;
+        self
+    }
+
+    /// Set how long an HTTP/1 connection must be idle before being reused,
+    /// if `Pooled::set_last_idempotent` says its last request wasn't
+    /// idempotent. `None` means no wait, the default.
+    ///
+    /// Such a request may have failed in a way that left the connection
+    /// in an unknown state. Like a quarantined connection, it isn't given
+    /// to checkouts already waiting when it is returned.
+    pub(super) fn non_idempotent_cooldown(&mut self, dur: Option<Duration>) -> &mut PoolConfig {
+        self.non_idempotent_cooldown = dur;
+        self
+    }
+
+    /// Set how many errors recorded with `Pooled::record_error` retire a
+    /// connection, instead of it being pooled again. `None` means never,
+    /// which is the default.
+    pub(super) fn error_threshold(&mut self, max: Option<u64>) -> &mut PoolConfig {
+        self.error_threshold = max;
+        self
+    }
+
+    /// Set how long a connection may be checked out before the idle
+    /// interval logs a warning that it may have been leaked, once per
+    /// connection. `None` means never, which is the default.
+    ///
+    /// This needs the idle interval, so it only works with a `timeout`.
+    pub(super) fn leak_warning(&mut self, after: Option<Duration>) -> &mut PoolConfig {
+        self.leak_warning = after;
+        self
+    }
+
+    /// Set how many keys `Pool::metrics_text` lists on their own in each
+    /// per-key metric, with the rest summed as `host="other"`. Defaults
+    /// to 10.
+    ///
+    /// The most active keys are picked, and each stays listed for as long
+    /// as the pool has stats for it.
+    pub(super) fn metrics_top_keys(&mut self, max: usize) -> &mut PoolConfig {
+        self.metrics_top_keys = max;
+        self
+    }
+
+    /// Set whether a checkout that finds only expired connections may still
+    /// use the most recently idle one, if it isn't closed. Defaults to
+    /// `false`.
+    ///
+    /// This prefers reusing a connection over paying for a dial, at the
+    /// risk of the server having already closed its end.
+    pub(super) fn reuse_expired(&mut self, enabled: bool) -> &mut PoolConfig {
+        self.reuse_expired = enabled;
+        self
+    }
+
+    /// Set which idle connection a checkout picks. Defaults to
+    /// `IdleOrder::Lifo`.
+    pub(super) fn idle_order(&mut self, order: IdleOrder) -> &mut PoolConfig {
+        self.idle_order = order;
+        self
+    }
+
+    /// Set whether a checkout traces each closed or expired connection it
+    /// removes.
+    ///
+    /// By default, only a summary per key is traced, at most about once a
+    /// second, so a flapping backend doesn't flood the logs.
+    pub(super) fn trace_each_removal(&mut self, enabled: bool) -> &mut PoolConfig {
+        self.trace_each_removal = enabled;
+        self
+    }
+
+    /// Set the most idle connections each key keeps. `None` means no
+    /// limit, the default.
+    ///
+    /// When a key is full, a connection that becomes idle is closed
+    /// instead of pooled, rather than closing one already idle. Since an
+    /// HTTP/2 connection is pooled once however many handles it has, this
+    /// counts connections, not handles.
+    pub(super) fn max_idle_per_host(&mut self, max: Option<usize>) -> &mut PoolConfig {
+        self.max_idle_per_host = max;
+        self
+    }
+
+    /// Limit how many checkouts per second each key may start, allowing
+    /// bursts of up to `burst` at once. `None` means no limit, which is the
+    /// default.
+    ///
+    /// A checkout over the limit waits for the limit to allow it, even if
+    /// there are idle connections.
+    pub(super) fn checkout_rate_limit(&mut self, per_sec: Option<u32>, burst: u32) -> &mut PoolConfig {
+        self.checkout_rate = per_sec.map(|per_sec| RateLimit::new(per_sec, burst));
+        self
+    }
+
+    /// Count the streams checked out on each HTTP/2 connection, which can
+    /// have up to `max` at once. `None` turns counting off, which is the
+    /// default.
+    ///
+    /// When a key has more than one HTTP/2 connection, checkouts take the
+    /// one with the fewest streams in use, instead of following
+    /// `idle_order`. A connection with all `max` streams in use isn't
+    /// handed out at all. If every connection for the key is like that,
+    /// checkouts wait for a stream to be given back, and `Pool::connecting`
+    /// doesn't start another dial for them.
+    pub(super) fn http2_max_streams(&mut self, max: Option<usize>) -> &mut PoolConfig {
+        self.http2_max_streams = max;
+        self
+    }
+}
+
+/// Which of a host's idle connections a request uses, see
+/// `client::Builder::pool_idle_order`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdleOrder {
+    /// The most recently idled connection, which is the cheapest to find.
+    Lifo,
+    /// The connection with the longest time left before it expires, which
+    /// means searching all of the host's idle connections.
+    Freshest,
+    /// A random connection, where those with more time left before they
+    /// expire are more likely. This also searches all of them, and
+    /// spreads load over connections instead of always using the same one.
+    WeightedRandom,
+}
+
 impl<T> Pool<T> {
+    #[cfg(test)]
     pub fn new(enabled: bool, timeout: Option<Duration>) -> Pool<T> {
+        let mut config = PoolConfig::new();
+        config
+            .enabled(enabled)
+            .timeout(timeout);
+        Pool::with_config(config)
+    }
+
+    /// Create a pool with all the settings of `config`.
+    pub(super) fn with_config(config: PoolConfig) -> Pool<T> {
         Pool {
             inner: Arc::new(Mutex::new(PoolInner {
-                connecting: HashSet::new(),
-                enabled: enabled,
+                activity: HashMap::new(),
+                backoff: HashMap::new(),
+                busy: HashMap::new(),
+                busy_warned: HashSet::new(),
+                checkout_tokens: HashMap::new(),
+                closed_rejections: HashMap::new(),
+                config: config,
+                connecting: HashMap::new(),
+                dials_coalesced: 0,
+                dials_in_flight: HashMap::new(),
+                dials_started: 0,
+                #[cfg(test)]
+                events: Vec::new(),
                 idle: HashMap::new(),
                 idle_interval_ref: None,
+                metrics_keys: Vec::new(),
+                next_park_id: 0,
                 parked: HashMap::new(),
-                timeout: timeout,
+                parked_entries_died: 0,
+                random: None,
+                reaper: ReaperStats {
+                    last_reap_at: None,
+                    last_reap_count: 0,
+                    reaped_total: 0,
+                },
+                removed_log: HashMap::new(),
+                streams: HashMap::new(),
             })),
         }
     }
+
+    /// Set the random number generator used by `IdleOrder::WeightedRandom`,
+    /// such as a seeded one for tests.
+    #[cfg(test)]
+    pub(super) fn set_random<F>(&self, random: F)
+    where
+        F: FnMut() -> u64 + Send + 'static,
+    {
+        self.inner.lock().unwrap().random = Some(Box::new(random));
+    }
 }
 
 impl<T: Poolable> Pool<T> {
     /// Returns a `Checkout` which is a future that resolves if an idle
     /// connection becomes available.
+    ///
+    /// A default port in the key's authority is ignored, so
+    /// `https://example.com:443` and `https://example.com` share
+    /// connections.
     pub fn checkout(&self, key: Key) -> Checkout<T> {
         Checkout {
-            key,
+            backoff_timer: None,
+            cooldown_timer: None,
+            quarantine_timer: None,
+            key: normalize_key(key),
             pool: self.clone(),
+            park_id: 0,
+            park_reason: None,
+            park_timer: None,
             parked: None,
+            rate_timer: None,
+            started: false,
+            wants: Wants::default(),
         }
     }
 
     /// Ensure that there is only ever 1 connecting task for HTTP/2
     /// connections. This does nothing for HTTP/1.
+    ///
+    /// While dials for the key are backing off after connect failures,
+    /// this returns `None` for either version, see `dial_allowed_at`. It
+    /// also returns `None` while every HTTP/2 connection for the key has
+    /// all of its streams in use, since checkouts wait for one of those
+    /// instead (see `PoolConfig::http2_max_streams`).
     pub(super) fn connecting(&self, key: &Key) -> Option<Connecting<T>> {
+        // The connection is pooled under the same key its checkouts use.
+        let key = &normalize_key(key.clone());
+        // One lock for both checks, so a connect failure recorded in between
+        // can't be missed.
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(at) = inner.dial_allowed_at(key) {
+            trace!("dial backoff for {:?} until {:?}", key.0, at);
+            return None;
+        }
+        if inner.saturated(key) {
+            trace!("HTTP/2 connections for {:?} are saturated, waiting for a stream", key.0);
+            return None;
+        }
         if key.1 == Ver::Http2 {
-            let mut inner = self.inner.lock().unwrap();
-            if inner.connecting.insert(key.clone()) {
+            let window = inner.config.connect_coalesce_window;
+            let now = Instant::now();
+            let start = match inner.connecting.get_mut(key) {
+                Some(dialing) => {
+                    let slow = window
+                        .map(|window| now - dialing.started_at >= window)
+                        .unwrap_or(false);
+                    if slow {
+                        trace!("HTTP/2 connecting exceeded coalesce window for {:?}", key.0);
+                        // Restart the window, so there is at most one more
+                        // dial per window.
+                        dialing.started_at = now;
+                        dialing.count += 1;
+                    }
+                    slow
+                },
+                None => {
+                    inner.connecting.insert(key.clone(), Dialing {
+                        count: 1,
+                        started_at: now,
+                    });
+                    true
+                },
+            };
+            if start {
+                inner.dials_started += 1;
+                inner.dial_started(key);
                 let connecting = Connecting {
                     key: key.clone(),
-                    pool: Arc::downgrade(&self.inner),
+                    meta: Meta::new(),
+                                        pool: Arc::downgrade(&self.inner),
+                    tracker: Arc::downgrade(&self.inner),
                 };
                 Some(connecting)
             } else {
                 trace!("HTTP/2 connecting already in progress for {:?}", key.0);
+                inner.dials_coalesced += 1;
                 None
             }
         } else {
+            inner.dial_started(key);
             Some(Connecting {
                 key: key.clone(),
-                // in HTTP/1's case, there is never a lock, so we don't
+                meta: Meta::new(),
+                                // in HTTP/1's case, there is never a lock, so we don't
                 // need to do anything in Drop.
                 pool: Weak::new(),
+                tracker: Arc::downgrade(&self.inner),
             })
         }
     }
 
-    fn take(&self, key: &Key) -> Option<Pooled<T>> {
-        let entry = {
-            let mut inner = self.inner.lock().unwrap();
-            let expiration = Expiration::new(inner.timeout);
-            let maybe_entry = inner.idle.get_mut(key)
-                .and_then(|list| {
-                    trace!("take? {:?}: expiration = {:?}", key, expiration.0);
-                    // A block to end the mutable borrow on list,
-                    // so the map below can check is_empty()
-                    {
-                        let popper = IdlePopper {
-                            key,
-                            list,
-                        };
-                        popper.pop(&expiration)
-                    }
-                        .map(|e| (e, list.is_empty()))
-                });
+    /// Record that connecting for this key failed, pushing back when the
+    /// next dial is allowed.
+    pub(super) fn connect_failed(&self, key: &Key) {
+        let key = &normalize_key(key.clone());
+        let mut inner = self.inner.lock().unwrap();
+        let config = inner.config.backoff.clone();
+        let backoff = inner.backoff.entry(key.clone())
+            .or_insert(Backoff {
+                failures: 0,
+                next_dial_at: Instant::now(),
+            });
+        backoff.failures = backoff.failures.saturating_add(1);
+        let delay = backoff.delay(&config);
+        trace!("dial backoff for {:?}: {:?} after {} failures", key, delay, backoff.failures);
+        backoff.next_dial_at = Instant::now() + delay;
+    }
+
+    /// If dials for this key are currently backing off, returns when the
+    /// next dial may start.
+    pub(super) fn dial_allowed_at(&self, key: &Key) -> Option<Instant> {
+        let key = &normalize_key(key.clone());
+        self.inner.lock().unwrap().dial_allowed_at(key)
+    }
+
+    /// If an HTTP/2 dial for this key is in progress and there is a
+    /// `PoolConfig::connect_coalesce_window`, returns when `connecting`
+    /// will allow another dial.
+    pub(super) fn coalesce_window_ends_at(&self, key: &Key) -> Option<Instant> {
+        let key = &normalize_key(key.clone());
+        if key.1 != Ver::Http2 {
+            return None;
+        }
+        let inner = self.inner.lock().unwrap();
+        let window = match inner.config.connect_coalesce_window {
+            Some(window) => window,
+            None => return None,
+        };
+        inner.connecting.get(key).map(|dialing| dialing.started_at + window)
+    }
 
-            let (entry, empty) = if let Some((e, empty)) = maybe_entry {
-                (Some(e), empty)
+    /// Get a snapshot of statistics about this pool.
+    pub(super) fn stats(&self) -> PoolStats {
+        let inner = self.inner.lock().unwrap();
+        let mut idle_by_host = HashMap::new();
+        for (key, list) in &inner.idle {
+            *idle_by_host.entry((*key.0).clone()).or_insert(0) += list.len();
+        }
+        PoolStats {
+            closed_rejections: inner.closed_rejections.clone(),
+            connecting: inner.connecting.values().map(|dialing| dialing.count).sum(),
+            dials_coalesced: inner.dials_coalesced,
+            dials_in_flight: inner.dials_in_flight.clone(),
+            dials_started: inner.dials_started,
+            idle_connections: inner.idle.values().map(|list| list.len()).sum(),
+            idle_by_host,
+            parked_entries_died: inner.parked_entries_died,
+            parked_waiters: inner.parked.values().map(|parked| parked.len()).sum(),
+            reaper: if inner.idle_interval_ref.is_some() {
+                Some(inner.reaper.clone())
             } else {
-                // No entry found means nuke the list for sure.
-                (None, true)
+                None
+            },
+        }
+    }
+
+    /// Render the pool's current stats in the Prometheus text exposition
+    /// format, with each metric name starting with `prefix`.
+    ///
+    /// Per-key metrics only list some keys on their own, see
+    /// `PoolConfig::metrics_top_keys`, so the number of lines stays
+    /// bounded however many hosts are used. Every listed key has a line in
+    /// each per-key metric, even if its value is 0.
+    pub(super) fn metrics_text(&self, prefix: &str) -> String {
+        let mut inner = self.inner.lock().unwrap();
+        let keys = inner.metrics_keys();
+        let inner = &*inner;
+        let mut out = String::new();
+        {
+            let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(Option<String>, u64)>| {
+                out.push_str(&format!("# HELP {}_{} {}\n", prefix, name, help));
+                out.push_str(&format!("# TYPE {}_{} {}\n", prefix, name, kind));
+                for (labels, value) in samples {
+                    match labels {
+                        Some(labels) => out.push_str(&format!("{}_{}{{{}}} {}\n", prefix, name, labels, value)),
+                        None => out.push_str(&format!("{}_{} {}\n", prefix, name, value)),
+                    }
+                }
             };
-            if empty {
-                //TODO: This could be done with the HashMap::entry API instead.
-                inner.idle.remove(key);
-            }
-            entry
-        };
+            let total = |value: u64| vec![(None, value)];
+            // The listed keys in a stable order, then everything else.
+            let per_key = |values: Vec<(&Key, u64)>| {
+                let mut samples = keys.iter()
+                    .map(|key| (Some(key_labels(key)), 0))
+                    .collect::<Vec<_>>();
+                let mut other = 0;
+                for (key, value) in values {
+                    match keys.iter().position(|listed| listed == key) {
+                        Some(i) => samples[i].1 = value,
+                        None => other += value,
+                    }
+                }
+                samples.push((Some("host=\"other\",version=\"other\"".to_string()), other));
+                samples
+            };
+
+            let idle = inner.idle.values().map(|list| list.len() as u64).sum();
+            metric("idle_connections", "gauge", "Idle connections in the pool.", total(idle));
+            metric(
+                "idle_connections_by_key",
+                "gauge",
+                "Idle connections in the pool for each key.",
+                per_key(inner.idle.iter().map(|(key, list)| (key, list.len() as u64)).collect()),
+            );
+            let parked = inner.parked.values().map(|parked| parked.len() as u64).sum();
+            metric("parked_checkouts", "gauge", "Checkouts waiting for a connection.", total(parked));
+            metric(
+                "checked_out_connections",
+                "gauge",
+                "Connections currently checked out.",
+                total(inner.busy.len() as u64),
+            );
+            metric(
+                "closed_rejections_total",
+                "counter",
+                "Idle connections a checkout found closed, for each key.",
+                per_key(inner.closed_rejections.iter().map(|(key, &count)| (key, count)).collect()),
+            );
+            metric(
+                "reaped_connections_total",
+                "counter",
+                "Closed or expired connections removed by the idle interval.",
+                total(inner.reaper.reaped_total),
+            );
+            metric(
+                "parked_entries_died_total",
+                "counter",
+                "Connections found closed when delivered to a waiting checkout.",
+                total(inner.parked_entries_died),
+            );
+            metric("dials_started_total", "counter", "HTTP/2 dials started.", total(inner.dials_started));
+            metric(
+                "dials_coalesced_total",
+                "counter",
+                "HTTP/2 dials that waited on one already in progress.",
+                total(inner.dials_coalesced),
+            );
+            metric(
+                "dials_in_flight",
+                "gauge",
+                "Dials in progress for each key.",
+                per_key(inner.dials_in_flight.iter().map(|(key, counts)| (key, counts.current as u64)).collect()),
+            );
+            metric(
+                "dials_in_flight_peak",
+                "gauge",
+                "The most dials that were in progress at once for each key.",
+                per_key(inner.dials_in_flight.iter().map(|(key, counts)| (key, counts.peak as u64)).collect()),
+            );
+        }
+        out
+    }
+
+    /// The number of connections currently checked out, until their
+    /// `Pooled` is dropped.
+    ///
+    /// If this keeps growing while the load doesn't, `Pooled`s are likely
+    /// being leaked.
+    #[cfg(test)]
+    pub(super) fn outstanding_count(&self) -> usize {
+        self.inner.lock().unwrap().busy.len()
+    }
+
+    /// Drain the events recorded since the last call, oldest first.
+    #[cfg(test)]
+    fn take_events(&self) -> Vec<PoolEvent> {
+        ::std::mem::replace(&mut self.inner.lock().unwrap().events, Vec::new())
+    }
 
-        entry.map(|e| self.reuse(key, e.value))
+    #[cfg(test)]
+    fn take(&self, key: &Key, wants: &Wants) -> Option<Pooled<T>> {
+        self.take_entry(key, wants).map(|e| self.reuse(key, e.value, e.meta))
+    }
+
+    fn take_entry(&self, key: &Key, wants: &Wants) -> Option<Idle<T>> {
+        self.inner.lock().unwrap().take_idle(key, wants)
     }
 
     pub(super) fn pooled(&self, mut connecting: Connecting<T>, value: T) -> Pooled<T> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            // A successful connection resets any dial backoff.
+            inner.backoff.remove(&connecting.key);
+            // The dial is done, even if `connecting` is kept around as the
+            // lock of a deferred HTTP/2 connection.
+            if connecting.tracker.upgrade().is_some() {
+                inner.dial_finished(&connecting.key);
+                connecting.tracker = Weak::new();
+            }
+        }
+        let key = connecting.key.clone();
+        let mut meta = connecting.meta.clone();
+        meta.created_at = Instant::now();
+        let mut deferred = None;
+        let mut has_stream = false;
         let (value, pool_ref) = match value.reserve() {
             Reservation::Shared(to_insert, to_return) => {
                 debug_assert_eq!(
-                    connecting.key.1,
+                    key.1,
                     Ver::Http2,
                     "shared reservation without Http2"
                 );
                 let mut inner = self.inner.lock().unwrap();
-                inner.put(connecting.key.clone(), to_insert);
-                // Do this here instead of Drop for Connecting because we
-                // already have a lock, no need to lock the mutex twice.
-                inner.connected(&connecting.key);
-                // prevent the Drop of Connecting from repeating inner.connected()
-                connecting.pool = Weak::new();
-
-                // Shared reservations don't need a reference to the pool,
-                // since the pool always keeps a copy.
-                (to_return, Weak::new())
+                // The dialer's request is the connection's first stream,
+                // counted before any parked checkout is given the connection.
+                if inner.config.http2_max_streams.is_some() {
+                    inner.open_stream(meta.conn_id);
+                    has_stream = true;
+                }
+                if inner.config.defer_http2_pooling {
+                    trace!("deferring pooling new HTTP/2 connection for {:?}", key);
+                    // The returned handle inserts itself on drop, like a
+                    // unique reservation, and holds the connecting lock
+                    // until then.
+                    drop(to_insert);
+                    deferred = Some(connecting);
+                    (to_return, Arc::downgrade(&self.inner))
+                } else {
+                    inner.put(key.clone(), to_insert, meta.clone());
+                    // Do this here instead of Drop for Connecting because we
+                    // already have a lock, no need to lock the mutex twice.
+                    if connecting.pool.upgrade().is_some() {
+                        inner.connected(&connecting.key);
+                    }
+                    // prevent the Drop of Connecting from repeating inner.connected()
+                    connecting.pool = Weak::new();
+
+                    // Shared reservations don't need a reference to the pool,
+                    // since the pool always keeps a copy.
+                    (to_return, Weak::new())
+                }
             },
             Reservation::Unique(value) => {
                 // Unique reservations must take a reference to the pool
@@ -186,15 +874,23 @@ impl<T: Poolable> Pool<T> {
             },
         };
         Pooled {
+            deferred: deferred,
+            has_stream,
+            id: Some(self.track_busy(&key)),
+            is_quarantined: false,
             is_reused: false,
-            key: connecting.key.clone(),
+            key: key,
+            meta,
             pool: pool_ref,
+            tracker: Arc::downgrade(&self.inner),
             value: Some(value)
         }
     }
 
-    fn reuse(&self, key: &Key, value: T) -> Pooled<T> {
+    fn reuse(&self, key: &Key, value: T, mut meta: Meta) -> Pooled<T> {
         debug!("reuse idle connection for {:?}", key);
+        // That was about the request before this checkout's.
+        meta.after_non_idempotent = false;
         // TODO: unhack this
         // In Pool::pooled(), which is used for inserting brand new connections,
         // there's some code that adjusts the pool reference taken depending
@@ -210,35 +906,292 @@ impl<T: Poolable> Pool<T> {
         };
 
         Pooled {
+            deferred: None,
+            has_stream: false,
+            id: Some(self.track_busy(key)),
+            is_quarantined: false,
             is_reused: true,
             key: key.clone(),
+            meta,
             pool: pool_ref,
+            tracker: Arc::downgrade(&self.inner),
             value: Some(value),
         }
     }
 
-    fn park(&mut self, key: Key, tx: oneshot::Sender<T>) {
+    /// Start tracking a connection being checked out, returning the id for
+    /// its `Pooled`.
+    fn track_busy(&self, key: &Key) -> usize {
+        let id = NEXT_POOLED_ID.fetch_add(1, Ordering::Relaxed);
+        self.inner.lock().unwrap().busy.insert(id, (key.clone(), Instant::now()));
+        id
+    }
+
+    fn park(&mut self, key: Key, tx: oneshot::Sender<Option<(T, Meta)>>) -> Result<usize, CheckoutError> {
         trace!("checkout waiting for idle connection: {:?}", key);
-        self.inner.lock().unwrap()
-            .parked.entry(key)
+        let mut inner = self.inner.lock().unwrap();
+        let max_parked = inner.config.max_parked_per_host;
+        if let Some(max) = max_parked {
+            // Canceled waiters shouldn't count against the limit.
+            inner.clean_parked(&key);
+            let parked = inner.parked.get(&key).map(|parked| parked.len()).unwrap_or(0);
+            if parked >= max {
+                debug!("too many checkouts waiting for {:?}", key);
+                return Err(CheckoutError::Busy);
+            }
+        }
+        let id = inner.next_park_id;
+        inner.next_park_id = id.wrapping_add(1);
+        #[cfg(test)]
+        inner.events.push(PoolEvent::Park(key.clone()));
+        inner.parked.entry(key)
             .or_insert(VecDeque::new())
-            .push_back(tx);
+            .push_back((id, tx));
+        Ok(id)
+    }
+}
+
+/// A snapshot of statistics about a `Pool`.
+#[derive(Clone, Debug)]
+pub(super) struct PoolStats {
+    /// For each key, the number of idle connections a checkout found to be
+    /// closed and had to skip.
+    ///
+    /// A high count for one host suggests its server closes idle
+    /// connections sooner than the pool's idle timeout.
+    pub closed_rejections: HashMap<Key, u64>,
+    /// The number of HTTP/2 connections being established.
+    pub connecting: usize,
+    /// The number of HTTP/2 dials that were able to wait on a dial already
+    /// in progress, instead of starting their own.
+    pub dials_coalesced: u64,
+    /// For each key dialed so far, how many dials are in flight, and the
+    /// most that ever were at once.
+    pub dials_in_flight: HashMap<Key, DialCounts>,
+    /// The number of HTTP/2 dials that were started.
+    pub dials_started: u64,
+    /// The number of idle connections, including quarantined ones.
+    pub idle_connections: usize,
+    /// The number of idle connections for each host, whatever their
+    /// version.
+    pub idle_by_host: HashMap<String, usize>,
+    /// The number of connections delivered to a waiting checkout that were
+    /// found to be closed on arrival.
+    pub parked_entries_died: u64,
+    /// The number of checkouts waiting for a connection. Some may have
+    /// been canceled and not cleaned up yet.
+    pub parked_waiters: usize,
+    /// What the idle interval has done, if it was started.
+    pub reaper: Option<ReaperStats>,
+}
+
+/// Counts of the dials in flight to a host, from `PoolStats::dials_in_flight`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DialCounts {
+    current: usize,
+    peak: usize,
+}
+
+impl DialCounts {
+    /// How many dials are still in progress.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// The most that were in progress at once.
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+}
+
+/// What the background cleanup of expired idle connections has done, from
+/// `PoolStats::reaper`.
+#[derive(Clone, Debug)]
+pub struct ReaperStats {
+    last_reap_at: Option<Instant>,
+    last_reap_count: usize,
+    reaped_total: u64,
+}
+
+impl ReaperStats {
+    /// When expired connections were last looked for.
+    ///
+    /// If this is much longer ago than the idle timeout, the cleanup has
+    /// likely stopped running.
+    pub fn last_reap_at(&self) -> Option<Instant> {
+        self.last_reap_at
+    }
+
+    /// How many connections were removed the last time.
+    pub fn last_reap_count(&self) -> usize {
+        self.last_reap_count
+    }
+
+    /// How many connections have been removed in total.
+    pub fn reaped_total(&self) -> u64 {
+        self.reaped_total
     }
 }
 
 /// Pop off this list, looking for a usable connection that hasn't expired.
 struct IdlePopper<'a, T: 'a> {
+    // How many closed connections were removed.
+    closed: u64,
+    // How many expired connections were removed.
+    expired: u64,
     key: &'a Key,
     list: &'a mut Vec<Idle<T>>,
+    // How many streams a connection can have, when `streams` is set.
+    max_streams: usize,
+    non_idempotent_cooldown: Option<Duration>,
+    order: IdleOrder,
+    random: Option<&'a mut Random>,
+    reuse_expired: bool,
+    scan_past_closed: bool,
+    // The streams in use on each connection, for HTTP/2 keys when they
+    // are counted.
+    streams: Option<&'a HashMap<u64, Streams>>,
+    trace_each_removal: bool,
 }
 
 impl<'a, T: Poolable + 'a> IdlePopper<'a, T> {
-    fn pop(self, expiration: &Expiration) -> Option<Idle<T>> {
+    fn pop(&mut self, expiration: &Expiration, quarantine: Duration, wants: &Wants) -> Option<Idle<T>> {
+        // Connections that are usable, just not right now or not by this
+        // checkout, are put back in their original order once the search
+        // is over.
+        let mut skipped = Vec::new();
+        if let Some(streams) = self.streams {
+            self.move_least_loaded_last(streams, expiration, quarantine);
+        } else if self.order == IdleOrder::Freshest {
+            self.move_freshest_last(expiration, quarantine);
+        } else if self.order == IdleOrder::WeightedRandom {
+            self.move_weighted_last(expiration, quarantine);
+        }
+        let found = self.pop_usable(expiration, quarantine, wants, &mut skipped);
+        self.list.extend(skipped.into_iter().rev());
+        found
+    }
+
+    /// Move a random usable connection to the end of the list, so it is the
+    /// next one popped.
+    ///
+    /// Each is weighted by the milliseconds left before it expires. Those
+    /// that never expire get the largest weight of the others, so if none
+    /// expire, each is as likely.
+    fn move_weighted_last(&mut self, expiration: &Expiration, quarantine: Duration) {
+        let now = Instant::now();
+        let mut candidates = Vec::new();
+        for (i, entry) in self.list.iter().enumerate() {
+            if entry.value.is_closed() || expiration.expires_idle(entry) {
+                continue;
+            }
+            if !self.acceptable(entry, quarantine) {
+                continue;
+            }
+            let weight = expiration.deadline(entry).map(|deadline| {
+                let left = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+                left.as_secs() * 1000 + u64::from(left.subsec_nanos()) / 1_000_000 + 1
+            });
+            candidates.push((i, weight));
+        }
+        let max_weight = candidates.iter()
+            .filter_map(|&(_, weight)| weight)
+            .max()
+            .unwrap_or(1);
+        let total: u64 = candidates.iter()
+            .map(|&(_, weight)| weight.unwrap_or(max_weight))
+            .sum();
+        if total == 0 {
+            return;
+        }
+        let r = match self.random {
+            Some(ref mut random) => random(),
+            None => RandomState::new().build_hasher().finish(),
+        };
+        let mut point = r % total;
+        for &(i, weight) in &candidates {
+            let weight = weight.unwrap_or(max_weight);
+            if point < weight {
+                let entry = self.list.remove(i);
+                self.list.push(entry);
+                return;
+            }
+            point -= weight;
+        }
+    }
+
+    /// Move the usable connection with the fewest streams in use to the end
+    /// of the list, so it is the next one popped.
+    fn move_least_loaded_last(&mut self, streams: &HashMap<u64, Streams>, expiration: &Expiration, quarantine: Duration) {
+        let mut best: Option<(usize, usize)> = None;
+        for (i, entry) in self.list.iter().enumerate() {
+            if entry.value.is_closed() || expiration.expires_idle(entry) {
+                continue;
+            }
+            if !self.acceptable(entry, quarantine) {
+                continue;
+            }
+            let in_use = streams.get(&entry.meta.conn_id)
+                .map(|streams| streams.in_use)
+                .unwrap_or(0);
+            // On a tie, prefer the later entry, same as `Lifo`.
+            if best.map(|(_, fewest)| in_use <= fewest).unwrap_or(true) {
+                best = Some((i, in_use));
+            }
+        }
+        if let Some((i, _)) = best {
+            let entry = self.list.remove(i);
+            self.list.push(entry);
+        }
+    }
+
+    /// Move the usable connection that expires last to the end of the list,
+    /// so it is the next one popped.
+    fn move_freshest_last(&mut self, expiration: &Expiration, quarantine: Duration) {
+        let mut best: Option<(usize, Option<Instant>)> = None;
+        for (i, entry) in self.list.iter().enumerate() {
+            if entry.value.is_closed() || expiration.expires_idle(entry) {
+                continue;
+            }
+            if !self.acceptable(entry, quarantine) {
+                continue;
+            }
+            let deadline = expiration.deadline(entry);
+            // On a tie, prefer the later entry, same as `Lifo`.
+            let fresher = match best {
+                None => true,
+                Some((_, best_deadline)) => match (deadline, best_deadline) {
+                    (None, _) => true,
+                    (Some(_), None) => false,
+                    (Some(deadline), Some(best_deadline)) => deadline >= best_deadline,
+                },
+            };
+            if fresher {
+                best = Some((i, deadline));
+            }
+        }
+        if let Some((i, _)) = best {
+            let entry = self.list.remove(i);
+            self.list.push(entry);
+        }
+    }
+
+    fn pop_usable(&mut self, expiration: &Expiration, quarantine: Duration, wants: &Wants, skipped: &mut Vec<Idle<T>>) -> Option<Idle<T>> {
+        // With `reuse_expired`, the first expired connection that could
+        // otherwise be used, in case nothing better is found.
+        let mut fallback = None;
         while let Some(entry) = self.list.pop() {
             // If the connection has been closed, or is older than our idle
             // timeout, simply drop it and keep looking...
             if entry.value.is_closed() {
-                trace!("removing closed connection for {:?}", self.key);
+                if self.trace_each_removal {
+                    trace!("removing closed connection for {:?}", self.key);
+                }
+                self.closed += 1;
+                if !self.scan_past_closed {
+                    wants.stopped_early.set(!self.list.is_empty() || !skipped.is_empty());
+                    break;
+                }
                 continue;
             }
             // TODO: Actually, since the `idle` list is pushed to the end always,
@@ -247,67 +1200,195 @@ impl<'a, T: Poolable + 'a> IdlePopper<'a, T> {
             //
             // In that case, we could just break out of the loop and drop the
             // whole list...
-            if expiration.expires(entry.idle_at) {
-                trace!("removing expired connection for {:?}", self.key);
+            //
+            // (Not quite, since a connection can also set its own expiry.)
+            if expiration.expires_idle(&entry) {
+                if self.reuse_expired && fallback.is_none() && self.acceptable(&entry, quarantine) {
+                    fallback = Some(entry);
+                    continue;
+                }
+                if self.trace_each_removal {
+                    trace!("removing expired connection for {:?}", self.key);
+                }
+                self.expired += 1;
                 continue;
             }
-
-            let value = match entry.value.reserve() {
-                Reservation::Shared(to_reinsert, to_checkout) => {
-                    self.list.push(Idle {
-                        idle_at: Instant::now(),
-                        value: to_reinsert,
-                    });
-                    to_checkout
-                },
-                Reservation::Unique(unique) => {
-                    unique
+            if let Some(at) = entry.quarantined_at {
+                if at.elapsed() < quarantine {
+                    trace!("skipping quarantined connection for {:?}", self.key);
+                    skipped.push(entry);
+                    continue;
                 }
-            };
+            }
+            if self.saturated(&entry) {
+                trace!("skipping HTTP/2 connection with every stream in use for {:?}", self.key);
+                wants.saturated.set(true);
+                skipped.push(entry);
+                continue;
+            }
+            if self.cooling_down(&entry) {
+                trace!("skipping connection cooling down after a non-idempotent request for {:?}", self.key);
+                skipped.push(entry);
+                continue;
+            }
 
-            return Some(Idle {
-                idle_at: entry.idle_at,
-                value,
-            });
+            if fallback.take().is_some() {
+                self.expired += 1;
+            }
+            return Some(self.reserve(entry));
+        }
+
+        fallback.map(|entry| {
+            trace!("reusing expired connection for {:?}", self.key);
+            self.reserve(entry)
+        })
+    }
+
+    /// Whether a connection that isn't closed or expired may be handed out
+    /// to this checkout.
+    fn acceptable(&self, entry: &Idle<T>, quarantine: Duration) -> bool {
+        let quarantined = entry.quarantined_at
+            .map(|at| at.elapsed() < quarantine)
+            .unwrap_or(false);
+        !quarantined &&
+            !self.saturated(entry) &&
+            !self.cooling_down(entry)
+    }
+
+    /// Whether every stream of this HTTP/2 connection is in use.
+    fn saturated(&self, entry: &Idle<T>) -> bool {
+        match self.streams {
+            Some(streams) => streams.get(&entry.meta.conn_id)
+                .map(|streams| streams.in_use >= self.max_streams)
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Whether the connection's last request wasn't idempotent, and it
+    /// hasn't been idle for `PoolConfig::non_idempotent_cooldown` yet.
+    fn cooling_down(&self, entry: &Idle<T>) -> bool {
+        match self.non_idempotent_cooldown {
+            Some(cooldown) => entry.meta.after_non_idempotent && entry.idle_at.elapsed() < cooldown,
+            None => false,
         }
+    }
+
+    fn reserve(&mut self, entry: Idle<T>) -> Idle<T> {
+        let mut meta = entry.meta;
+        meta.reuse_count += 1;
+        let value = match entry.value.reserve() {
+            Reservation::Shared(to_reinsert, to_checkout) => {
+                self.list.push(Idle {
+                    idle_at: Instant::now(),
+                    meta: meta.clone(),
+                    quarantined_at: None,
+                    value: to_reinsert,
+                });
+                to_checkout
+            },
+            Reservation::Unique(unique) => {
+                unique
+            }
+        };
 
-        None
+        Idle {
+            idle_at: entry.idle_at,
+            meta,
+            quarantined_at: None,
+            value,
+        }
     }
 }
 
 impl<T: Poolable> PoolInner<T> {
-    fn put(&mut self, key: Key, value: T) {
-        if !self.enabled {
+    fn put(&mut self, key: Key, value: T, meta: Meta) {
+        if !self.config.enabled {
             return;
         }
-        if key.1 == Ver::Http2 && self.idle.contains_key(&key) {
+        self.bump_activity(&key);
+        if !self.removed_log.is_empty() {
+            self.flush_removed_logs(Instant::now());
+        }
+        #[cfg(test)]
+        self.events.push(PoolEvent::Put(key.clone()));
+        // The pool keeps a copy of each HTTP/2 connection while it is
+        // checked out, so it may already be here.
+        let already_idle = key.1 == Ver::Http2 && self.idle.get(&key)
+            .map(|list| list.iter().any(|entry| entry.meta.conn_id == meta.conn_id))
+            .unwrap_or(false);
+        if already_idle {
             trace!("put; existing idle HTTP/2 connection for {:?}", key);
             return;
         }
         trace!("put; add idle connection for {:?}", key);
+        // Like a quarantined connection, one cooling down isn't given to
+        // waiters.
+        let cooling_down = meta.after_non_idempotent &&
+            key.1 == Ver::Http1 &&
+            self.config.non_idempotent_cooldown.is_some();
+        let value = if cooling_down {
+            Some((value, meta))
+        } else {
+            self.send_parked(&key, value, meta)
+        };
+
+        match value {
+            Some((value, meta)) => {
+                if self.is_full(&key) {
+                    trace!("put; max idle per host reached, closing connection for {:?}", key);
+                    return;
+                }
+                debug!("pooling idle connection for {:?}", key);
+                if cooling_down {
+                    // Parked checkouts park again with a timer for when the
+                    // cooldown ends, so the connection isn't missed.
+                    self.wake_parked(&key);
+                }
+                self.idle.entry(key).or_insert(Vec::new()).push(Idle {
+                    value: value,
+                    idle_at: Instant::now(),
+                    meta,
+                    quarantined_at: None,
+                });
+            }
+            None => trace!("put; found waiter for {:?}", key),
+        }
+    }
+
+    /// Try to give a connection to the checkouts parked on `key`.
+    ///
+    /// Gives back the connection if it wasn't sent, or the copy to keep if
+    /// it was shared.
+    fn send_parked(&mut self, key: &Key, value: T, meta: Meta) -> Option<(T, Meta)> {
         let mut remove_parked = false;
-        let mut value = Some(value);
-        if let Some(parked) = self.parked.get_mut(&key) {
-            while let Some(tx) = parked.pop_front() {
+        let mut value = Some((value, meta));
+        if let Some(parked) = self.parked.get_mut(key) {
+            while let Some((_, tx)) = parked.pop_front() {
                 if !tx.is_canceled() {
-                    let reserved = value.take().expect("value already sent");
+                    let (reserved, mut meta) = value.take().expect("value already sent");
+                    meta.reuse_count += 1;
                     let reserved = match reserved.reserve() {
                         Reservation::Shared(to_keep, to_send) => {
-                            value = Some(to_keep);
+                            value = Some((to_keep, meta.clone()));
                             to_send
                         },
                         Reservation::Unique(uniq) => uniq,
                     };
-                    match tx.send(reserved) {
+                    match tx.send(Some((reserved, meta))) {
                         Ok(()) => {
+                            #[cfg(test)]
+                            self.events.push(PoolEvent::CompleteParked(key.clone()));
                             if value.is_none() {
                                 break;
                             } else {
                                 continue;
                             }
                         },
-                        Err(e) => {
-                            value = Some(e);
+                        Err(unsent) => {
+                            let (e, mut meta) = unsent.expect("sent a connection");
+                            meta.reuse_count -= 1;
+                            value = Some((e, meta));
                         }
                     }
                 }
@@ -317,31 +1398,235 @@ impl<T: Poolable> PoolInner<T> {
             remove_parked = parked.is_empty();
         }
         if remove_parked {
-            self.parked.remove(&key);
+            self.parked.remove(key);
         }
+        value
+    }
 
-        match value {
-            Some(value) => {
-                debug!("pooling idle connection for {:?}", key);
-                self.idle.entry(key)
-                     .or_insert(Vec::new())
-                     .push(Idle {
-                         value: value,
-                         idle_at: Instant::now(),
-                     });
+    /// Whether `key` has open HTTP/2 connections, but all of their streams
+    /// are in use.
+    fn saturated(&self, key: &Key) -> bool {
+        let max = match self.config.http2_max_streams {
+            Some(max) if key.1 == Ver::Http2 => max,
+            _ => return false,
+        };
+        let streams = &self.streams;
+        match self.idle.get(key) {
+            Some(list) => {
+                let mut open = list.iter().filter(|entry| !entry.value.is_closed()).peekable();
+                open.peek().is_some() && open.all(|entry| {
+                    streams.get(&entry.meta.conn_id)
+                        .map(|streams| streams.in_use >= max)
+                        .unwrap_or(false)
+                })
+            },
+            None => false,
+        }
+    }
+
+    fn take_idle(&mut self, key: &Key, wants: &Wants) -> Option<Idle<T>> {
+        let expiration = Expiration::new(self.config.timeout);
+        let quarantine = self.config.quarantine_duration;
+        let scan_past_closed = self.config.scan_past_closed;
+        let order = self.config.idle_order;
+        let trace_each_removal = self.config.trace_each_removal;
+        let reuse_expired = self.config.reuse_expired;
+        let non_idempotent_cooldown = if key.1 == Ver::Http1 {
+            self.config.non_idempotent_cooldown
+        } else {
+            None
+        };
+        let random = self.random.as_mut().map(|f| &mut **f);
+        let (streams, max_streams) = match self.config.http2_max_streams {
+            Some(max) if key.1 == Ver::Http2 => (Some(&self.streams), max),
+            _ => (None, 0),
+        };
+        let maybe_entry = self.idle.get_mut(key)
+            .map(|list| {
+                trace!("take? {:?}: expiration = {:?}", key, expiration.0);
+                // A block to end the mutable borrow on list,
+                // so the tuple below can check is_empty()
+                let (entry, closed, expired) = {
+                    let mut popper = IdlePopper {
+                        closed: 0,
+                        expired: 0,
+                        key,
+                        list,
+                        max_streams,
+                        non_idempotent_cooldown,
+                        order,
+                        random,
+                        reuse_expired,
+                        scan_past_closed,
+                        streams,
+                        trace_each_removal,
+                    };
+                    let entry = popper.pop(&expiration, quarantine, wants);
+                    (entry, popper.closed, popper.expired)
+                };
+                // Even if no entry was found, the list may still hold
+                // quarantined connections.
+                (entry, list.is_empty(), closed, expired)
+            });
+
+        let (entry, empty, closed, expired) = maybe_entry.unwrap_or((None, true, 0, 0));
+        if closed > 0 {
+            *self.closed_rejections.entry(key.clone()).or_insert(0) += closed;
+        }
+        if !trace_each_removal && (closed > 0 || expired > 0) {
+            self.log_removed(key, closed, expired);
+        }
+        #[cfg(test)]
+        {
+            for _ in 0..closed {
+                self.events.push(PoolEvent::Evict(key.clone(), EvictReason::Closed));
             }
-            None => trace!("put; found waiter for {:?}", key),
+            for _ in 0..expired {
+                self.events.push(PoolEvent::Evict(key.clone(), EvictReason::Expired));
+            }
+            if entry.is_some() {
+                self.events.push(PoolEvent::Take(key.clone()));
+            }
+        }
+        if empty {
+            //TODO: This could be done with the HashMap::entry API instead.
+            self.idle.remove(key);
+        }
+        if entry.is_some() {
+            self.bump_activity(key);
+        }
+        entry
+    }
+
+    /// Whether `key` already has `max_idle_per_host` usable idle
+    /// connections. Closed and expired ones are evicted first, and
+    /// quarantined ones aren't counted, so they can't keep a fresh
+    /// connection out of the pool.
+    fn is_full(&mut self, key: &Key) -> bool {
+        match self.config.max_idle_per_host {
+            Some(max) => self.usable_idle(key) >= max,
+            None => false,
+        }
+    }
+
+    /// Count `key`'s usable idle connections, for `max_idle_per_host`.
+    /// Closed and expired ones are evicted first, and quarantined ones
+    /// aren't counted.
+    fn usable_idle(&mut self, key: &Key) -> usize {
+        let expiration = Expiration::new(self.config.timeout);
+        let quarantine = self.config.quarantine_duration;
+        let now = Instant::now();
+        #[cfg(test)]
+        let events = &mut self.events;
+        let usable = match self.idle.get_mut(key) {
+            Some(list) => {
+                list.retain(|entry| {
+                    let reason = if entry.value.is_closed() {
+                        EvictReason::Closed
+                    } else if expiration.expires_idle_at(entry, now) {
+                        EvictReason::Expired
+                    } else {
+                        return true;
+                    };
+                    trace!("put; evicting {:?} connection for {:?}", reason, key);
+                    #[cfg(test)]
+                    events.push(PoolEvent::Evict(key.clone(), reason));
+                    false
+                });
+                list.iter()
+                    .filter(|entry| entry.quarantined_at.map(|at| now - at >= quarantine).unwrap_or(true))
+                    .count()
+            },
+            None => 0,
+        };
+        usable
+    }
+
+    fn bump_activity(&mut self, key: &Key) {
+        let now = Instant::now();
+        let activity = self.activity.entry(key.clone()).or_insert(Activity {
+            score: 0,
+            updated_at: now,
+        });
+        activity.decay(now);
+        activity.score = activity.score.saturating_add(1);
+    }
+
+    /// Count connections removed by `take`, logging a summary for the key
+    /// at most about once a second.
+    fn log_removed(&mut self, key: &Key, closed: u64, expired: u64) {
+        let now = Instant::now();
+        {
+            let log = self.removed_log.entry(key.clone()).or_insert_with(|| RemovedLog {
+                closed: 0,
+                expired: 0,
+                since: now,
+            });
+            log.closed += closed;
+            log.expired += expired;
+        }
+        self.flush_removed_logs(now);
+    }
+
+    /// Log the summaries of removals from `take` that are at least a
+    /// second old, for every key. Keys that went quiet are flushed by
+    /// whatever the pool does next, instead of waiting for their own next
+    /// removal.
+    fn flush_removed_logs(&mut self, now: Instant) {
+        self.removed_log.retain(|key, log| {
+            if now - log.since >= Duration::from_secs(1) {
+                log.trace(key, now);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Like `put`, but the connection is not given to any parked waiters,
+    /// and `take` will skip it until the `quarantine_duration` has passed.
+    fn quarantine(&mut self, key: Key, value: T, meta: Meta) {
+        if !self.config.enabled {
+            return;
+        }
+        if self.is_full(&key) {
+            trace!("quarantine; max idle per host reached, closing connection for {:?}", key);
+            return;
         }
+        debug!("quarantining connection for {:?}", key);
+        // Parked checkouts park again with a timer for when the quarantine
+        // ends, so the connection isn't missed.
+        self.wake_parked(&key);
+        let now = Instant::now();
+        self.idle.entry(key)
+            .or_insert(Vec::new())
+            .push(Idle {
+                value: value,
+                idle_at: now,
+                meta,
+                quarantined_at: Some(now),
+            });
     }
 
     /// A `Connecting` task is complete. Not necessarily successfully,
     /// but the lock is going away, so clean up.
     fn connected(&mut self, key: &Key) {
-        let existed = self.connecting.remove(key);
-        debug_assert!(
-            existed,
-            "Connecting dropped, key not in pool.connecting"
-        );
+        let done = match self.connecting.get_mut(key) {
+            Some(dialing) => {
+                dialing.count -= 1;
+                dialing.count == 0
+            },
+            None => {
+                debug_assert!(false, "Connecting dropped, key not in pool.connecting");
+                return;
+            }
+        };
+        if !done {
+            // Another dial for this key is still going, and the waiters
+            // may yet get its connection.
+            return;
+        }
+        self.connecting.remove(key);
         // cancel any waiters. if there are any, it's because
         // this Connecting task didn't complete successfully.
         // those waiters would never receive a connection.
@@ -350,6 +1635,196 @@ impl<T: Poolable> PoolInner<T> {
 }
 
 impl<T> PoolInner<T> {
+    fn dial_started(&mut self, key: &Key) {
+        let counts = self.dials_in_flight.entry(key.clone()).or_insert(DialCounts::default());
+        counts.current += 1;
+        counts.peak = cmp::max(counts.peak, counts.current);
+    }
+
+    fn dial_finished(&mut self, key: &Key) {
+        if let Some(counts) = self.dials_in_flight.get_mut(key) {
+            counts.current = counts.current.saturating_sub(1);
+        }
+    }
+
+    /// Take a token to start a checkout for `key`, or get how long until
+    /// one is available.
+    fn take_checkout_token(&mut self, key: &Key) -> Result<(), Duration> {
+        let limit = match self.config.checkout_rate {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let bucket = self.checkout_tokens.entry(key.clone()).or_insert(TokenBucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        });
+        bucket.refill(&limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let secs = (1.0 - bucket.tokens) / limit.per_sec as f64;
+            let nanos = (secs * 1_000_000_000.0).ceil() as u64;
+            trace!("checkout rate limited for {:?}", key);
+            Err(Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32))
+        }
+    }
+
+    /// Count a stream checked out on an HTTP/2 connection.
+    fn open_stream(&mut self, conn_id: u64) {
+        self.streams.entry(conn_id).or_insert_with(Streams::default).in_use += 1;
+    }
+
+    /// Give back a stream opened with `open_stream` on an HTTP/2 connection
+    /// for `key`.
+    ///
+    /// Checkouts parked on `key` may have been held back from the
+    /// connection, so they are woken up to look again.
+    fn close_stream(&mut self, key: &Key, conn_id: u64) {
+        self.wake_parked(key);
+        let empty = match self.streams.get_mut(&conn_id) {
+            Some(streams) => {
+                streams.in_use -= 1;
+                streams.in_use == 0
+            },
+            None => {
+                // This can run while a `Pooled` is dropped, so don't panic
+                // over it outside of debug builds.
+                debug_assert!(false, "close_stream: stream was never opened");
+                trace!("no stream open on connection {}", conn_id);
+                return;
+            },
+        };
+        if empty {
+            self.streams.remove(&conn_id);
+        }
+    }
+
+    /// Wake up the checkouts parked on `key`, without a connection, so they
+    /// check the idle list again.
+    fn wake_parked(&mut self, key: &Key) {
+        if let Some(parked) = self.parked.remove(key) {
+            for (_, tx) in parked {
+                let _ = tx.send(None);
+            }
+        }
+    }
+
+    /// When the first of `key`'s idle connections cooling down after a
+    /// non-idempotent request can be used again, if any are.
+    fn cooldown_ends_at(&self, key: &Key) -> Option<Instant> {
+        let cooldown = match self.config.non_idempotent_cooldown {
+            Some(cooldown) if key.1 == Ver::Http1 => cooldown,
+            _ => return None,
+        };
+        let now = Instant::now();
+        self.idle.get(key).and_then(|list| {
+            list.iter()
+                .filter(|entry| entry.meta.after_non_idempotent)
+                .map(|entry| entry.idle_at + cooldown)
+                .filter(|&at| at > now)
+                .min()
+        })
+    }
+
+    /// When the first of `key`'s quarantined idle connections can be used
+    /// again, if any are quarantined.
+    fn quarantine_ends_at(&self, key: &Key) -> Option<Instant> {
+        let quarantine = self.config.quarantine_duration;
+        let now = Instant::now();
+        self.idle.get(key).and_then(|list| {
+            list.iter()
+                .filter_map(|entry| entry.quarantined_at)
+                .map(|at| at + quarantine)
+                .filter(|&at| at > now)
+                .min()
+        })
+    }
+
+    /// Pick the keys `Pool::metrics_text` lists on their own.
+    ///
+    /// Keys already listed stay listed while the pool has stats for them,
+    /// so their series don't come and go between scrapes. Any free places
+    /// go to the most active keys, with ties broken by `key_order`.
+    fn metrics_keys(&mut self) -> Vec<Key> {
+        let mut candidates = self.idle.keys()
+            .chain(self.closed_rejections.keys())
+            .chain(self.dials_in_flight.keys())
+            .cloned()
+            .collect::<HashSet<_>>();
+        let top = self.config.metrics_top_keys;
+        self.metrics_keys.retain(|key| candidates.contains(key));
+        self.metrics_keys.truncate(top);
+        for key in &self.metrics_keys {
+            candidates.remove(key);
+        }
+
+        let now = Instant::now();
+        let activity = &mut self.activity;
+        let mut rest = candidates.into_iter()
+            .map(|key| {
+                let score = activity.get_mut(&key).map(|activity| {
+                    activity.decay(now);
+                    activity.score
+                }).unwrap_or(0);
+                (score, key)
+            })
+            .collect::<Vec<_>>();
+        rest.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| key_order(&a.1).cmp(&key_order(&b.1))));
+        let free = top.saturating_sub(self.metrics_keys.len());
+        self.metrics_keys.extend(rest.into_iter().take(free).map(|(_, key)| key));
+        self.metrics_keys.clone()
+    }
+
+    /// Stop tracking a checked out connection, by its `Pooled::id`.
+    fn untrack(&mut self, id: usize) {
+        self.busy.remove(&id);
+        self.busy_warned.remove(&id);
+    }
+
+    /// Release excess capacity held by the pool's lists and maps, such as
+    /// after a burst of connections has been closed.
+    ///
+    /// This is O(keys), so it is left to the idle interval.
+    fn shrink_to_fit(&mut self) {
+        for list in self.idle.values_mut() {
+            list.shrink_to_fit();
+        }
+        for parked in self.parked.values_mut() {
+            parked.shrink_to_fit();
+        }
+        // Rehashing the maps isn't free, so only bother if they are mostly
+        // empty space.
+        fn is_sparse(len: usize, cap: usize) -> bool {
+            len < cap / 4
+        }
+        if is_sparse(self.idle.len(), self.idle.capacity()) {
+            self.idle.shrink_to_fit();
+        }
+        if is_sparse(self.parked.len(), self.parked.capacity()) {
+            self.parked.shrink_to_fit();
+        }
+        if is_sparse(self.connecting.len(), self.connecting.capacity()) {
+            self.connecting.shrink_to_fit();
+        }
+        if is_sparse(self.backoff.len(), self.backoff.capacity()) {
+            self.backoff.shrink_to_fit();
+        }
+    }
+
+    fn dial_allowed_at(&mut self, key: &Key) -> Option<Instant> {
+        let next = match self.backoff.get(key) {
+            Some(backoff) => backoff.next_dial_at,
+            None => return None,
+        };
+        if next > Instant::now() {
+            Some(next)
+        } else {
+            None
+        }
+    }
+
     /// Any `FutureResponse`s that were created will have made a `Checkout`,
     /// and possibly inserted into the pool that it is waiting for an idle
     /// connection. If a user ever dropped that future, we need to clean out
@@ -357,7 +1832,7 @@ impl<T> PoolInner<T> {
     fn clean_parked(&mut self, key: &Key) {
         let mut remove_parked = false;
         if let Some(parked) = self.parked.get_mut(key) {
-            parked.retain(|tx| {
+            parked.retain(|&(_, ref tx)| {
                 !tx.is_canceled()
             });
             remove_parked = parked.is_empty();
@@ -369,41 +1844,103 @@ impl<T> PoolInner<T> {
 }
 
 impl<T: Poolable> PoolInner<T> {
-    /// This should *only* be called by the IdleInterval.
-    fn clear_expired(&mut self) {
-        let dur = self.timeout.expect("interval assumes timeout");
+    /// This should *only* be called by the IdleInterval, or
+    /// `Pool::poll_expired`.
+    fn clear_expired(&mut self, now: Instant) {
+        assert!(self.config.timeout.is_some(), "interval assumes timeout");
 
-        let now = Instant::now();
-        //self.last_idle_check_at = now;
+        let reaped = self.evict_unusable(now);
 
-        self.idle.retain(|key, values| {
-            values.retain(|entry| {
-                if entry.value.is_closed() {
-                    trace!("idle interval evicting closed for {:?}", key);
-                    return false;
-                }
-                if now - entry.idle_at > dur {
-                    trace!("idle interval evicting expired for {:?}", key);
-                    return false;
+        // Waiters that gave up would otherwise stay until their key next
+        // gets a connection.
+        let keys = self.parked.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            self.clean_parked(&key);
+        }
+
+        if let Some(after) = self.config.leak_warning {
+            for (&id, &(ref key, since)) in &self.busy {
+                if now - since > after && self.busy_warned.insert(id) {
+                    warn!("connection for {:?} checked out for {:?}, it may have been leaked", key, now - since);
                 }
+            }
+        }
 
-                // Otherwise, keep this value...
-                true
+        // A bucket that has filled back up is the same as a new one.
+        match self.config.checkout_rate {
+            Some(limit) => self.checkout_tokens.retain(|_, bucket| {
+                bucket.refill(&limit, now);
+                bucket.tokens < limit.burst as f64
+            }),
+            None => self.checkout_tokens.clear(),
+        }
+
+        // Don't let summaries of removals from `take` wait for a next one.
+        self.flush_removed_logs(now);
+
+        self.shrink_to_fit();
+
+        self.reaper.last_reap_at = Some(now);
+        self.reaper.last_reap_count = reaped;
+        self.reaper.reaped_total += reaped as u64;
+    }
+
+    /// Remove every closed idle connection, or one expired as of `now`,
+    /// returning how many were removed.
+    fn evict_unusable(&mut self, now: Instant) -> usize {
+        let expiration = Expiration::new(self.config.timeout);
+        let mut reaped = 0;
+
+        #[cfg(test)]
+        let events = &mut self.events;
+        self.idle.retain(|key, values| {
+            let before = values.len();
+            values.retain(|entry| {
+                let reason = if entry.value.is_closed() {
+                    EvictReason::Closed
+                } else if expiration.expires_idle_at(entry, now) {
+                    EvictReason::Expired
+                } else {
+                    // Otherwise, keep this value...
+                    return true;
+                };
+                trace!("idle interval evicting {:?} connection for {:?}", reason, key);
+                #[cfg(test)]
+                events.push(PoolEvent::Evict(key.clone(), reason));
+                false
             });
+            reaped += before - values.len();
 
             // returning false evicts this key/val
             !values.is_empty()
         });
+        reaped
     }
 }
 
 
 impl<T: Poolable + Send + 'static> Pool<T> {
+    /// Remove every idle connection that is closed, or expired as of `now`,
+    /// and any parked checkouts that were canceled, returning how many
+    /// connections were removed.
+    ///
+    /// This is what the idle interval does, for when it can't be spawned.
+    /// Without a `timeout`, this does nothing.
+    #[cfg(test)]
+    pub(super) fn poll_expired(&self, now: Instant) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.config.timeout.is_none() {
+            return 0;
+        }
+        inner.clear_expired(now);
+        inner.reaper.last_reap_count
+    }
+
     pub(super) fn spawn_expired_interval(&self, exec: &Exec) {
         let (dur, rx) = {
             let mut inner = self.inner.lock().unwrap();
 
-            if !inner.enabled {
+            if !inner.config.enabled {
                 return;
             }
 
@@ -411,10 +1948,10 @@ impl<T: Poolable + Send + 'static> Pool<T> {
                 return;
             }
 
-            if let Some(dur) = inner.timeout {
+            if let Some(dur) = inner.config.timeout {
                 let (tx, rx) = oneshot::channel();
                 inner.idle_interval_ref = Some(tx);
-                (dur, rx)
+                (inner.config.reap_interval.unwrap_or(dur), rx)
             } else {
                 return
             }
@@ -441,16 +1978,105 @@ impl<T> Clone for Pool<T> {
 // Note: The bounds `T: Poolable` is needed for the Drop impl.
 pub(super) struct Pooled<T: Poolable> {
     value: Option<T>,
+    // A new HTTP/2 connection that hasn't been pooled yet. Dropping this
+    // *after* inserting into the pool lets any waiting checkouts get it.
+    deferred: Option<Connecting<T>>,
+    // Whether this is an HTTP/2 stream counted in the pool's `streams`.
+    has_stream: bool,
+    // Identifies this handle in the pool's tracking of checked out
+    // connections. Taken once the pool has stopped tracking it.
+    id: Option<usize>,
+    is_quarantined: bool,
     is_reused: bool,
     key: Key,
+    meta: Meta,
     pool: Weak<Mutex<PoolInner<T>>>,
+    // Unlike `pool`, this is set even for shared reservations, so that
+    // checked out connections can still be tracked.
+    tracker: Weak<Mutex<PoolInner<T>>>,
 }
 
+static NEXT_POOLED_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+static NEXT_CONN_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
 impl<T: Poolable> Pooled<T> {
+    /// Whether this connection was taken from the pool, instead of being
+    /// newly connected.
+    ///
+    /// This doesn't mean a request was already served on it. A new HTTP/2
+    /// connection is pooled right away, so its other handles are reused
+    /// without it having served anything.
     pub fn is_reused(&self) -> bool {
         self.is_reused
     }
 
+    /// Stop tracking this connection as checked out, and give back its
+    /// HTTP/2 stream, if one was counted.
+    fn release(&mut self, inner: &mut PoolInner<T>) {
+        if let Some(id) = self.id.take() {
+            inner.untrack(id);
+        }
+        if self.has_stream {
+            self.has_stream = false;
+            inner.close_stream(&self.key, self.meta.conn_id);
+        }
+    }
+
+    fn put_back(&self, inner: &mut PoolInner<T>, value: T) {
+        if let Some(max) = inner.config.error_threshold {
+            if self.meta.errors >= max {
+                debug!("retiring connection for {:?} after {} errors", self.key, self.meta.errors);
+                return;
+            }
+        }
+        let meta = self.meta.clone();
+        if self.is_quarantined {
+            inner.quarantine(self.key.clone(), value, meta);
+        } else {
+            inner.put(self.key.clone(), value, meta);
+        }
+    }
+
+    /// Mark that this connection saw a transient error.
+    ///
+    /// Instead of being immediately reusable when dropped, the connection
+    /// will sit in the pool for the `quarantine_duration` first.
+    ///
+    /// This only affects connections that are returned to the pool on
+    /// drop, so HTTP/2 connections are not affected.
+    pub fn quarantine(&mut self) {
+        self.is_quarantined = true;
+    }
+
+    /// Record that a request on this connection failed, even if the
+    /// connection itself can still be used.
+    ///
+    /// Errors stay with the connection as it is pooled, and it is retired
+    /// once `PoolConfig::error_threshold` are recorded. Like `quarantine`,
+    /// this doesn't affect HTTP/2 connections.
+    pub fn record_error(&mut self) {
+        self.meta.errors += 1;
+    }
+
+    /// Record whether the request sent on this connection is idempotent,
+    /// such as a `GET`, or not, such as a `POST`.
+    ///
+    /// See `PoolConfig::non_idempotent_cooldown`. This only applies until
+    /// the connection is checked out again, and doesn't affect HTTP/2
+    /// connections.
+    pub fn set_last_idempotent(&mut self, idempotent: bool) {
+        self.meta.after_non_idempotent = !idempotent;
+    }
+
+    /// Set how long this connection may be idle before it expires, instead
+    /// of the pool's idle timeout. `None` goes back to the pool's.
+    ///
+    /// This applies once the connection is returned to the pool, and stays
+    /// with it after.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.meta.idle_timeout = timeout;
+    }
+
     fn as_ref(&self) -> &T {
         self.value.as_ref().expect("not dropped")
     }
@@ -475,6 +2101,18 @@ impl<T: Poolable> DerefMut for Pooled<T> {
 
 impl<T: Poolable> Drop for Pooled<T> {
     fn drop(&mut self) {
+        // Declared first so it is dropped last, after the connection has
+        // been put into the pool.
+        let _deferred = self.deferred.take();
+
+        if self.id.is_some() || self.has_stream {
+            if let Some(inner) = self.tracker.upgrade() {
+                if let Ok(mut inner) = inner.lock() {
+                    self.release(&mut inner);
+                }
+            }
+        }
+
         if let Some(value) = self.value.take() {
             if value.is_closed() {
                 // If we *already* know the connection is done here,
@@ -484,7 +2122,7 @@ impl<T: Poolable> Drop for Pooled<T> {
 
             if let Some(inner) = self.pool.upgrade() {
                 if let Ok(mut inner) = inner.lock() {
-                    inner.put(self.key.clone(), value);
+                    self.put_back(&mut inner, value);
                 }
             } else if self.key.1 == Ver::Http1 {
                 trace!("pool dropped, dropping pooled ({:?})", self.key);
@@ -505,81 +2143,482 @@ impl<T: Poolable> fmt::Debug for Pooled<T> {
 
 struct Idle<T> {
     idle_at: Instant,
+    meta: Meta,
+    quarantined_at: Option<Instant>,
     value: T,
 }
 
+/// Details about a connection that stay with it as it moves between the
+/// idle list and checkouts.
+#[derive(Clone, Debug)]
+struct Meta {
+    // Whether the last request on this connection wasn't idempotent.
+    after_non_idempotent: bool,
+    // Unique to the connection, and the same for each HTTP/2 handle.
+    conn_id: u64,
+    created_at: Instant,
+    errors: u64,
+    // Used instead of the pool's idle timeout, such as from the server's
+    // `Keep-Alive` header.
+    idle_timeout: Option<Duration>,
+    reuse_count: usize,
+}
+
+impl Meta {
+    fn new() -> Meta {
+        Meta {
+            after_non_idempotent: false,
+            conn_id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed) as u64,
+            created_at: Instant::now(),
+            errors: 0,
+            idle_timeout: None,
+            reuse_count: 0,
+        }
+    }
+}
+
 pub(super) struct Checkout<T> {
+    // Set while parked during a dial backoff for the key, to wake the task
+    // once dialing is allowed again.
+    backoff_timer: Option<Delay>,
+    // Set while parked with a connection for the key cooling down, to
+    // check idle again once it can be used.
+    cooldown_timer: Option<Delay>,
     key: Key,
     pool: Pool<T>,
-    parked: Option<oneshot::Receiver<T>>,
+    // Identifies this checkout in the key's `parked` queue, while parked.
+    park_id: usize,
+    // Why this checkout last parked.
+    park_reason: Option<ParkReason>,
+    park_timer: Option<Delay>,
+    parked: Option<oneshot::Receiver<Option<(T, Meta)>>>,
+    // Set while parked with a connection for the key in quarantine, to
+    // check idle again once it can be used.
+    quarantine_timer: Option<Delay>,
+    // Set while waiting for the checkout rate limit to allow this checkout.
+    rate_timer: Option<Delay>,
+    // Whether the rate limit has let this checkout start.
+    started: bool,
+    wants: Wants,
+}
+
+/// Why a `Checkout` is parked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ParkReason {
+    /// There was no usable connection, so it waits for one to be dialed
+    /// or returned.
+    NoConnection,
+    /// Every HTTP/2 connection for the key had all of its streams in use,
+    /// see `PoolConfig::http2_max_streams`. It waits for one to be given
+    /// back instead of a dial.
+    Saturated,
+}
+
+/// What a `Checkout` requires of a connection, besides matching its key.
+#[derive(Default)]
+struct Wants {
+    // Set by a search of the idle list that skipped an HTTP/2 connection
+    // with every stream in use.
+    saturated: Cell<bool>,
+    // Set by a search of the idle list that stopped at a closed connection
+    // with others left, see `PoolConfig::scan_past_closed`.
+    stopped_early: Cell<bool>,
+}
+
+/// The outcome of polling a parked `Checkout`.
+enum Parked<T: Poolable> {
+    /// A connection was delivered, and is usable.
+    Delivered(Pooled<T>),
+    /// A connection was delivered, but was already closed. The checkout
+    /// should look for another one.
+    EntryDied,
+    /// The pool woke this checkout up without a connection, since one in
+    /// the idle list may now be usable.
+    Woken,
+    /// This checkout isn't parked.
+    Empty,
 }
 
 impl<T: Poolable> Checkout<T> {
-    fn poll_parked(&mut self) -> Poll<Option<Pooled<T>>, ::Error> {
-        static CANCELED: &str = "pool checkout failed";
-        if let Some(ref mut rx) = self.parked {
+    fn poll_parked(&mut self) -> Poll<Parked<T>, CheckoutError> {
+        let (value, meta) = if let Some(ref mut rx) = self.parked {
             match rx.poll() {
-                Ok(Async::Ready(value)) => {
-                    if !value.is_closed() {
-                        Ok(Async::Ready(Some(self.pool.reuse(&self.key, value))))
-                    } else {
-                        Err(::Error::new_canceled(Some(CANCELED)))
-                    }
+                Ok(Async::Ready(Some(value))) => value,
+                Ok(Async::Ready(None)) => {
+                    self.parked = None;
+                    return Ok(Async::Ready(Parked::Woken));
                 },
-                Ok(Async::NotReady) => Ok(Async::NotReady),
-                Err(_canceled) => Err(::Error::new_canceled(Some(CANCELED))),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_canceled) => return Err(CheckoutError::Canceled),
+            }
+        } else {
+            return Ok(Async::Ready(Parked::Empty));
+        };
+
+        // The receiver is spent either way.
+        self.parked = None;
+        if !value.is_closed() {
+            Ok(Async::Ready(Parked::Delivered(self.pool.reuse(&self.key, value, meta))))
+        } else {
+            trace!("parked checkout received closed connection for {:?}", self.key);
+            self.pool.inner.lock().unwrap().parked_entries_died += 1;
+            Ok(Async::Ready(Parked::EntryDied))
+        }
+    }
+
+    /// Hand `pooled` to the caller.
+    ///
+    /// If it is an HTTP/2 connection whose streams were all taken since it
+    /// was picked, this returns `None` and the checkout should look again.
+    fn deliver(&self, mut pooled: Pooled<T>) -> Option<Pooled<T>> {
+        if pooled.key.1 == Ver::Http2 && pooled.id.is_some() {
+            let saturated = {
+                let mut inner = self.pool.inner.lock().unwrap();
+                match inner.config.http2_max_streams {
+                    Some(max) => {
+                        let in_use = inner.streams.get(&pooled.meta.conn_id)
+                            .map(|streams| streams.in_use)
+                            .unwrap_or(0);
+                        if in_use < max {
+                            inner.open_stream(pooled.meta.conn_id);
+                            pooled.has_stream = true;
+                        }
+                        in_use >= max
+                    },
+                    None => false,
+                }
+            };
+            if saturated {
+                trace!("HTTP/2 connection for {:?} became saturated, looking again", self.key);
+                // Dropped without the lock, the pool keeps its own copy.
+                drop(pooled);
+                return None;
+            }
+        }
+        Some(pooled)
+    }
+
+    /// Whether the checkout rate limit lets this checkout start yet. If
+    /// not, the task is woken up once it might.
+    fn poll_started(&mut self) -> bool {
+        if self.started {
+            return true;
+        }
+        if let Some(ref mut timer) = self.rate_timer {
+            match timer.poll() {
+                Ok(Async::Ready(())) => (),
+                Ok(Async::NotReady) => return false,
+                // Without a timer, just try for a token again.
+                Err(_) => trace!("checkout rate timer failed"),
+            }
+        }
+        let token = self.pool.inner.lock().unwrap().take_checkout_token(&self.key);
+        match token {
+            Ok(()) => {
+                self.rate_timer = None;
+                self.started = true;
+                true
+            },
+            Err(wait) => {
+                let mut timer = Delay::new(wait);
+                let _ = timer.poll(); // register this task
+                self.rate_timer = Some(timer);
+                false
+            }
+        }
+    }
+
+    /// A future that resolves to this checkout once the checkout rate limit
+    /// lets it start.
+    ///
+    /// Anything racing the checkout, such as dialing a new connection, can
+    /// wait on this first so it is held to the same limit.
+    pub(super) fn started(self) -> Started<T> {
+        Started {
+            checkout: Some(self),
+        }
+    }
+
+    /// Whether this checkout has been parked for `max_park_duration`.
+    fn poll_park_timer(&mut self) -> bool {
+        if let Some(ref mut timer) = self.park_timer {
+            match timer.poll() {
+                Ok(Async::Ready(())) => true,
+                Ok(Async::NotReady) => false,
+                Err(_) => {
+                    // The timer thread is gone, so just wait for `put`.
+                    trace!("checkout park timer failed");
+                    false
+                }
             }
         } else {
-            Ok(Async::Ready(None))
+            false
+        }
+    }
+
+    /// Poll the timer for the end of the key's dial backoff, if any. Once
+    /// it fires, the task has been woken so whatever races this checkout
+    /// can dial again.
+    fn poll_backoff_timer(&mut self) {
+        let over = match self.backoff_timer {
+            Some(ref mut timer) => match timer.poll() {
+                Ok(Async::NotReady) => false,
+                Ok(Async::Ready(())) => true,
+                Err(_) => {
+                    trace!("checkout backoff timer failed");
+                    true
+                }
+            },
+            None => false,
+        };
+        if over {
+            trace!("dial backoff over, still waiting for {:?}", self.key);
+            self.backoff_timer = None;
         }
     }
 
-    fn park(&mut self) {
+    /// Whether a connection for the key this checkout is parked on has
+    /// finished cooling down.
+    fn poll_cooldown_timer(&mut self) -> bool {
+        poll_idle_timer(&mut self.cooldown_timer, "cooldown")
+    }
+
+    /// Whether a connection for the key this checkout is parked on has
+    /// finished its quarantine.
+    fn poll_quarantine_timer(&mut self) -> bool {
+        poll_idle_timer(&mut self.quarantine_timer, "quarantine")
+    }
+
+    fn park(&mut self) -> Result<(), CheckoutError> {
         if self.parked.is_none() {
             let (tx, mut rx) = oneshot::channel();
             let _ = rx.poll(); // park this task
-            self.pool.park(self.key.clone(), tx);
+            self.park_id = self.pool.park(self.key.clone(), tx)?;
             self.parked = Some(rx);
+
+            let (max_park, dial_at, cooldown_at, quarantine_at) = {
+                let mut inner = self.pool.inner.lock().unwrap();
+                (
+                    inner.config.max_park_duration,
+                    inner.dial_allowed_at(&self.key),
+                    inner.cooldown_ends_at(&self.key),
+                    inner.quarantine_ends_at(&self.key),
+                )
+            };
+            self.park_timer = max_park.map(|dur| {
+                let mut timer = Delay::new(dur);
+                let _ = timer.poll(); // register this task
+                timer
+            });
+            self.backoff_timer = dial_at.map(|at| {
+                let mut timer = Delay::new_at(at);
+                let _ = timer.poll(); // register this task
+                timer
+            });
+            self.cooldown_timer = cooldown_at.map(|at| {
+                let mut timer = Delay::new_at(at);
+                let _ = timer.poll(); // register this task
+                timer
+            });
+            self.quarantine_timer = quarantine_at.map(|at| {
+                let mut timer = Delay::new_at(at);
+                let _ = timer.poll(); // register this task
+                timer
+            });
         }
+        Ok(())
     }
 }
 
 impl<T: Poolable> Future for Checkout<T> {
     type Item = Pooled<T>;
-    type Error = ::Error;
+    type Error = CheckoutError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Some(pooled) = try_ready!(self.poll_parked()) {
-            return Ok(Async::Ready(pooled));
+        if !self.poll_started() {
+            return Ok(Async::NotReady);
+        }
+
+        match self.poll_parked()? {
+            Async::Ready(Parked::Delivered(pooled)) => if let Some(pooled) = self.deliver(pooled) {
+                return Ok(Async::Ready(pooled));
+            },
+            // Try to take or park again, as if newly polled.
+            Async::Ready(Parked::EntryDied) |
+            Async::Ready(Parked::Woken) |
+            Async::Ready(Parked::Empty) => (),
+            Async::NotReady => {
+                self.poll_backoff_timer();
+                if self.poll_cooldown_timer() {
+                    trace!("connection cooled down, checking idle again for {:?}", self.key);
+                } else if self.poll_quarantine_timer() {
+                    trace!("connection out of quarantine, checking idle again for {:?}", self.key);
+                } else if self.poll_park_timer() {
+                    trace!("checkout parked too long, checking idle again for {:?}", self.key);
+                } else {
+                    return Ok(Async::NotReady);
+                }
+                // Dropping the receiver cancels the old waiter.
+                self.parked = None;
+            }
         }
 
-        let entry = self.pool.take(&self.key);
+        self.wants.saturated.set(false);
+        self.wants.stopped_early.set(false);
+        let entry = self.pool.take_entry(&self.key, &self.wants);
 
-        if let Some(pooled) = entry {
-            Ok(Async::Ready(pooled))
+        if let Some(entry) = entry {
+            let pooled = self.pool.reuse(&self.key, entry.value, entry.meta);
+            match self.deliver(pooled) {
+                Some(pooled) => Ok(Async::Ready(pooled)),
+                None => self.poll(),
+            }
         } else {
-            self.park();
+            {
+                let mut inner = self.pool.inner.lock().unwrap();
+                if inner.config.backoff.fail_fast && inner.dial_allowed_at(&self.key).is_some() {
+                    return Err(CheckoutError::BackingOff);
+                }
+            }
+            if self.wants.stopped_early.get() {
+                trace!("idle search stopped at a closed connection for {:?}, not waiting", self.key);
+                return Err(CheckoutError::Canceled);
+            }
+            self.park_reason = if self.wants.saturated.get() {
+                trace!("checkout for {:?} waiting for an HTTP/2 stream", self.key);
+                Some(ParkReason::Saturated)
+            } else {
+                Some(ParkReason::NoConnection)
+            };
+            // If the pool is too busy to wait on, the Client will continue
+            // waiting on its own connect instead.
+            self.park()?;
             Ok(Async::NotReady)
         }
     }
 }
 
-impl<T> Drop for Checkout<T> {
-    fn drop(&mut self) {
-        self.parked.take();
-        if let Ok(mut inner) = self.pool.inner.lock() {
-            inner.clean_parked(&self.key);
-        }
+/// Poll a parked `Checkout`'s timer for when an idle connection it couldn't
+/// use yet becomes usable, clearing the timer once it fires.
+fn poll_idle_timer(timer: &mut Option<Delay>, what: &str) -> bool {
+    let over = match *timer {
+        Some(ref mut delay) => match delay.poll() {
+            Ok(Async::NotReady) => false,
+            Ok(Async::Ready(())) => true,
+            Err(_) => {
+                trace!("checkout {} timer failed", what);
+                false
+            }
+        },
+        None => false,
+    };
+    if over {
+        *timer = None;
     }
+    over
 }
 
-pub(super) struct Connecting<T: Poolable> {
-    key: Key,
-    pool: Weak<Mutex<PoolInner<T>>>,
+/// A future for a `Checkout` waiting on the checkout rate limit. See
+/// `Checkout::started`.
+pub(super) struct Started<T> {
+    checkout: Option<Checkout<T>>,
 }
 
-impl<T: Poolable> Drop for Connecting<T> {
+impl<T: Poolable> Future for Started<T> {
+    type Item = Checkout<T>;
+    type Error = CheckoutError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let started = self.checkout.as_mut().expect("polled after ready").poll_started();
+        if started {
+            Ok(Async::Ready(self.checkout.take().expect("polled after ready")))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Why a `Checkout` failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CheckoutError {
+    /// Dials for the key are backing off after failures, and
+    /// `PoolConfig::dial_backoff` was set to fail fast.
+    BackingOff,
+    /// Too many checkouts for the key were already waiting, see
+    /// `PoolConfig::max_parked_per_host`.
+    Busy,
+    /// The pool stopped waiting on a connection for this checkout, such as
+    /// when the only dial for an HTTP/2 key failed, or the idle search
+    /// stopped at a closed connection.
+    Canceled,
+}
+
+impl fmt::Display for CheckoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for CheckoutError {
+    fn description(&self) -> &str {
+        match *self {
+            CheckoutError::BackingOff => "dial backoff in effect",
+            CheckoutError::Busy => "pool is busy",
+            CheckoutError::Canceled => "pool checkout failed",
+        }
+    }
+}
+
+impl From<CheckoutError> for ::Error {
+    fn from(err: CheckoutError) -> ::Error {
+        match err {
+            CheckoutError::BackingOff => ::Error::new_connect(err),
+            CheckoutError::Busy |
+            CheckoutError::Canceled => ::Error::new_canceled(Some(err)),
+        }
+    }
+}
+
+impl<T> Drop for PoolInner<T> {
+    fn drop(&mut self) {
+        // Nothing else will flush these once the pool is gone.
+        let now = Instant::now();
+        for (key, log) in &self.removed_log {
+            log.trace(key, now);
+        }
+    }
+}
+
+impl<T> Drop for Checkout<T> {
+    fn drop(&mut self) {
+        // If this checkout isn't currently parked, it has no sender in the
+        // parked queue to clean up.
+        if self.parked.take().is_some() {
+            if let Ok(mut inner) = self.pool.inner.lock() {
+                inner.clean_parked(&self.key);
+            }
+        }
+    }
+}
+
+pub(super) struct Connecting<T: Poolable> {
+    key: Key,
+    meta: Meta,
+    pool: Weak<Mutex<PoolInner<T>>>,
+    // Unlike `pool`, this is set for HTTP/1 too, to count dials in flight.
+    tracker: Weak<Mutex<PoolInner<T>>>,
+}
+
+impl<T: Poolable> Connecting<T> {
+}
+
+impl<T: Poolable> Drop for Connecting<T> {
     fn drop(&mut self) {
+        if let Some(tracker) = self.tracker.upgrade() {
+            if let Ok(mut inner) = tracker.lock() {
+                inner.dial_finished(&self.key);
+            }
+        }
         if let Some(pool) = self.pool.upgrade() {
             // No need to panic on drop, that could abort!
             if let Ok(mut inner) = pool.lock() {
@@ -594,6 +2633,183 @@ impl<T: Poolable> Drop for Connecting<T> {
     }
 }
 
+/// Remove the port from a key's `scheme://authority` if it is the
+/// scheme's default, so explicit and implicit default ports are the same
+/// key.
+fn normalize_key(key: Key) -> Key {
+    let normalized = {
+        let s = &key.0[..];
+        let default_port = match s.find("://").map(|i| &s[..i]) {
+            Some("http") => Some(":80"),
+            Some("https") => Some(":443"),
+            _ => None,
+        };
+        default_port.and_then(|port| {
+            if s.ends_with(port) {
+                Some(s[..s.len() - port.len()].to_string())
+            } else {
+                None
+            }
+        })
+    };
+    match normalized {
+        Some(authority) => (Arc::new(authority), key.1),
+        None => key,
+    }
+}
+
+/// Escape a Prometheus label value.
+fn key_labels(key: &Key) -> String {
+    format!(
+        "host=\"{}\",version=\"{}\"",
+        escape_label(&key.0),
+        match key.1 {
+            Ver::Http1 => "http1",
+            Ver::Http2 => "http2",
+        },
+    )
+}
+
+/// Sorts keys by host, then version.
+fn key_order(key: &Key) -> (&str, u8) {
+    let ver = match key.1 {
+        Ver::Http1 => 0,
+        Ver::Http2 => 1,
+    };
+    (&key.0, ver)
+}
+
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Counts of connections removed for a key since `since`.
+struct RemovedLog {
+    closed: u64,
+    expired: u64,
+    since: Instant,
+}
+
+impl RemovedLog {
+    fn trace(&self, key: &Key, now: Instant) {
+        #[cfg(test)]
+        REMOVED_LOGGED.with(|logged| {
+            logged.borrow_mut().push(((*key.0).clone(), self.closed, self.expired));
+        });
+        trace!(
+            "removed {} closed and {} expired connections for {:?} in the last {:?}",
+            self.closed,
+            self.expired,
+            key,
+            now - self.since,
+        );
+    }
+}
+
+// The summaries logged by `RemovedLog::trace` on this thread, for tests to
+// check.
+#[cfg(test)]
+thread_local!(static REMOVED_LOGGED: ::std::cell::RefCell<Vec<(String, u64, u64)>> = ::std::cell::RefCell::new(Vec::new()));
+
+/// How fast checkouts may start, see `PoolConfig::checkout_rate_limit`.
+#[derive(Clone, Copy, Debug)]
+struct RateLimit {
+    burst: u32,
+    per_sec: u32,
+}
+
+impl RateLimit {
+    fn new(per_sec: u32, burst: u32) -> RateLimit {
+        RateLimit {
+            burst: cmp::max(burst, 1),
+            per_sec: cmp::max(per_sec, 1),
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now - self.updated_at;
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        self.tokens = (self.tokens + secs * limit.per_sec as f64).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+}
+
+struct Activity {
+    score: usize,
+    updated_at: Instant,
+}
+
+impl Activity {
+    /// Halve the score for each half-life passed since it was updated.
+    fn decay(&mut self, now: Instant) {
+        let secs = (now - self.updated_at).as_secs();
+        let halvings = secs / ACTIVITY_HALF_LIFE_SECS;
+        if halvings == 0 {
+            return;
+        }
+        self.score = if halvings >= 64 { 0 } else { self.score >> halvings };
+        self.updated_at += Duration::from_secs(halvings * ACTIVITY_HALF_LIFE_SECS);
+    }
+}
+
+const ACTIVITY_HALF_LIFE_SECS: u64 = 60;
+
+/// The streams checked out on one HTTP/2 connection.
+#[derive(Default)]
+struct Streams {
+    in_use: usize,
+}
+
+/// The HTTP/2 dials in progress for a key.
+struct Dialing {
+    // Usually 1, unless dials took longer than the coalesce window.
+    count: usize,
+    started_at: Instant,
+}
+
+#[derive(Clone, Debug)]
+struct BackoffConfig {
+    base: Duration,
+    max: Duration,
+    fail_fast: bool,
+}
+
+struct Backoff {
+    failures: u32,
+    next_dial_at: Instant,
+}
+
+impl Backoff {
+    /// Exponential backoff, with "equal jitter": the delay is somewhere
+    /// between half and all of the exponential value.
+    fn delay(&self, config: &BackoffConfig) -> Duration {
+        let exp = cmp::min(self.failures.saturating_sub(1), 31);
+        let full = config.base.checked_mul(1 << exp)
+            .map(|dur| cmp::min(dur, config.max))
+            .unwrap_or(config.max);
+        let half = full / 2;
+        // Not strong randomness, but enough to spread out dials.
+        let r = RandomState::new().build_hasher().finish() % 1000;
+        half + half * r as u32 / 1000
+    }
+}
+
 struct Expiration(Option<Duration>);
 
 impl Expiration {
@@ -601,9 +2817,25 @@ impl Expiration {
         Expiration(dur)
     }
 
-    fn expires(&self, instant: Instant) -> bool {
-        match self.0 {
-            Some(timeout) => instant.elapsed() > timeout,
+    /// The idle timeout of a connection, which is its own if it has one.
+    fn timeout(&self, meta: &Meta) -> Option<Duration> {
+        meta.idle_timeout.or(self.0)
+    }
+
+    /// When an idle connection will expire, or `None` if it never will.
+    fn deadline<T>(&self, entry: &Idle<T>) -> Option<Instant> {
+        self.timeout(&entry.meta).map(|timeout| entry.idle_at + timeout)
+    }
+
+    /// Whether an idle connection has expired.
+    fn expires_idle<T>(&self, entry: &Idle<T>) -> bool {
+        self.expires_idle_at(entry, Instant::now())
+    }
+
+    /// Like `expires_idle`, but as of `now`.
+    fn expires_idle_at<T>(&self, entry: &Idle<T>, now: Instant) -> bool {
+        match self.timeout(&entry.meta) {
+            Some(timeout) => now > entry.idle_at && now - entry.idle_at > timeout,
             None => false,
         }
     }
@@ -637,7 +2869,7 @@ impl<T: Poolable + 'static> Future for IdleInterval<T> {
 
             if let Some(inner) = self.pool.upgrade() {
                 if let Ok(mut inner) = inner.lock() {
-                    inner.clear_expired();
+                    inner.clear_expired(Instant::now());
                     continue;
                 }
             }
@@ -649,10 +2881,10 @@ impl<T: Poolable + 'static> Future for IdleInterval<T> {
 #[cfg(test)]
 mod tests {
     use std::sync::{Arc, Weak};
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use futures::{Async, Future};
     use futures::future;
-    use super::{Connecting, Key, Poolable, Pool, Reservation, Exec, Ver};
+    use super::{CheckoutError, Connecting, DialCounts, EvictReason, Key, Idle, IdleOrder, Meta, ParkReason, Poolable, Pool, PoolConfig, PoolEvent, REMOVED_LOGGED, Reservation, Exec, Ver, Wants};
 
     /// Test unique reservations.
     #[derive(Debug, PartialEq, Eq)]
@@ -668,11 +2900,11 @@ mod tests {
         }
     }
 
-    /*
+    /// Test shared reservations.
     #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     struct Share<T>(T);
 
-    impl<T> Poolable for Share<T> {
+    impl<T: Clone> Poolable for Share<T> {
         fn is_closed(&self) -> bool {
             false
         }
@@ -681,12 +2913,13 @@ mod tests {
             Reservation::Shared(self.clone(), self)
         }
     }
-    */
 
     fn c<T: Poolable>(key: Key) -> Connecting<T> {
         Connecting {
             key,
-            pool: Weak::new(),
+            meta: Meta::new(),
+                        pool: Weak::new(),
+            tracker: Weak::new(),
         }
     }
 
@@ -704,6 +2937,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_pool_disabled_doesnt_pool_http2() {
+        let pool = Pool::new(false, Some(Duration::from_secs(5)));
+        let key = (Arc::new("foo".to_string()), Ver::Http2);
+        let connecting = pool.connecting(&key).expect("connecting");
+        let pooled = pool.pooled(connecting, Share(41));
+
+        assert_eq!(*pooled, Share(41));
+        assert!(pool.inner.lock().unwrap().idle.is_empty());
+
+        drop(pooled);
+        assert!(pool.inner.lock().unwrap().idle.is_empty());
+    }
+
     #[test]
     fn test_pool_checkout_returns_none_if_expired() {
         future::lazy(|| {
@@ -711,12 +2958,59 @@ mod tests {
             let key = (Arc::new("foo".to_string()), Ver::Http1);
             let pooled = pool.pooled(c(key.clone()), Uniq(41));
             drop(pooled);
-            ::std::thread::sleep(pool.inner.lock().unwrap().timeout.unwrap());
+            ::std::thread::sleep(pool.inner.lock().unwrap().config.timeout.unwrap());
             assert!(pool.checkout(key).poll().unwrap().is_not_ready());
             ::futures::future::ok::<(), ()>(())
         }).wait().unwrap();
     }
 
+    #[test]
+    fn test_pool_checkout_skips_quarantined() {
+        future::lazy(|| {
+            let pool = Pool::new(true, Some(Duration::from_secs(10)));
+            pool.inner.lock().unwrap().config.quarantine_duration(Duration::from_millis(100));
+            let key = (Arc::new("foo".to_string()), Ver::Http1);
+            let mut pooled = pool.pooled(c(key.clone()), Uniq(41));
+            pooled.quarantine();
+            drop(pooled);
+
+            assert_eq!(pool.inner.lock().unwrap().idle.get(&key).map(|entries| entries.len()), Some(1));
+            assert!(pool.checkout(key.clone()).poll().unwrap().is_not_ready());
+            // still in the pool, just not usable yet
+            assert_eq!(pool.inner.lock().unwrap().idle.get(&key).map(|entries| entries.len()), Some(1));
+
+            ::std::thread::sleep(Duration::from_millis(100));
+            match pool.checkout(key).poll().unwrap() {
+                Async::Ready(pooled) => assert_eq!(*pooled, Uniq(41)),
+                _ => panic!("not ready"),
+            }
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_quarantine_wakes_parked() {
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        pool.inner.lock().unwrap().config.quarantine_duration(Duration::from_millis(50));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        let mut checkout = pool.checkout(key.clone());
+        assert!(future::lazy(|| checkout.poll()).wait().unwrap().is_not_ready());
+
+        // Quarantined, so it isn't given to the parked checkout now...
+        let usable_at = Instant::now() + Duration::from_millis(50);
+        let mut pooled = pool.pooled(c(key.clone()), Uniq(41));
+        pooled.quarantine();
+        drop(pooled);
+        assert!(pool.inner.lock().unwrap().parked.get(&key).is_none());
+
+        // ...but the checkout gets it once the quarantine is over.
+        let pooled = checkout.wait().unwrap();
+        assert_eq!(*pooled, Uniq(41));
+        assert!(Instant::now() >= usable_at);
+    }
+
     #[test]
     fn test_pool_checkout_removes_expired() {
         future::lazy(|| {
@@ -728,7 +3022,7 @@ mod tests {
             pool.pooled(c(key.clone()), Uniq(99));
 
             assert_eq!(pool.inner.lock().unwrap().idle.get(&key).map(|entries| entries.len()), Some(3));
-            ::std::thread::sleep(pool.inner.lock().unwrap().timeout.unwrap());
+            ::std::thread::sleep(pool.inner.lock().unwrap().config.timeout.unwrap());
 
             // checkout.poll() should clean out the expired
             pool.checkout(key.clone()).poll().unwrap();
@@ -761,13 +3055,72 @@ mod tests {
         assert!(pool.inner.lock().unwrap().idle.get(&key).is_none());
     }
 
+    #[test]
+    fn test_pool_stats_counts() {
+        future::lazy(|| {
+            let pool = Pool::new(true, Some(Duration::from_secs(10)));
+            let foo = (Arc::new("foo".to_string()), Ver::Http1);
+            let bar = (Arc::new("bar".to_string()), Ver::Http1);
+            let baz = (Arc::new("baz".to_string()), Ver::Http1);
+            let h2 = (Arc::new("h2".to_string()), Ver::Http2);
+
+            drop(pool.pooled(c(foo.clone()), Uniq(1)));
+            drop(pool.pooled(c(foo.clone()), Uniq(2)));
+            drop(pool.pooled(c(foo.clone()), Uniq(3)));
+            drop(pool.pooled(c(bar.clone()), Uniq(4)));
+            let mut parked1 = pool.checkout(baz.clone());
+            let mut parked2 = pool.checkout(baz.clone());
+            assert!(parked1.poll().unwrap().is_not_ready());
+            assert!(parked2.poll().unwrap().is_not_ready());
+            let _connecting = pool.connecting(&h2).expect("connecting");
+
+            let stats = pool.stats();
+            assert_eq!(stats.idle_connections, 4);
+            assert_eq!(stats.idle_by_host.len(), 2);
+            assert_eq!(stats.idle_by_host["foo"], 3);
+            assert_eq!(stats.idle_by_host["bar"], 1);
+            assert_eq!(stats.parked_waiters, 2);
+            assert_eq!(stats.connecting, 1);
+
+            let inner = pool.inner.lock().unwrap();
+            let idle = inner.idle.values().map(|list| list.len()).sum::<usize>();
+            assert_eq!(stats.idle_connections, idle);
+            assert_eq!(stats.parked_waiters, inner.parked[&baz].len());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_stats_reaper() {
+        use std::sync::Arc;
+        let runtime = ::tokio::runtime::Runtime::new().unwrap();
+        let pool = Pool::new(true, Some(Duration::from_millis(100)));
+        assert!(pool.stats().reaper.is_none());
+
+        let executor = runtime.executor();
+        pool.spawn_expired_interval(&Exec::Executor(Arc::new(executor)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        assert!(pool.stats().reaper.expect("reaper").last_reap_at.is_none());
+
+        pool.pooled(c(key.clone()), Uniq(41));
+        pool.pooled(c(key.clone()), Uniq(5));
+
+        ::futures_timer::Delay::new(
+            Duration::from_millis(400) // allow for too-good resolution
+        ).wait().unwrap();
+
+        let reaper = pool.stats().reaper.expect("reaper");
+        assert!(reaper.last_reap_at.is_some());
+        assert_eq!(reaper.reaped_total, 2);
+    }
+
     #[test]
     fn test_pool_checkout_task_unparked() {
         let pool = Pool::new(true, Some(Duration::from_secs(10)));
         let key = (Arc::new("foo".to_string()), Ver::Http1);
         let pooled = pool.pooled(c(key.clone()), Uniq(41));
 
-        let checkout = pool.checkout(key).join(future::lazy(move || {
+        let checkout = pool.checkout(key.clone()).join(future::lazy(move || {
             // the checkout future will park first,
             // and then this lazy future will be polled, which will insert
             // the pooled back into the pool
@@ -776,7 +3129,13 @@ mod tests {
             drop(pooled);
             Ok(())
         })).map(|(entry, _)| entry);
-        assert_eq!(*checkout.wait().unwrap(), Uniq(41));
+        let pooled = checkout.wait().unwrap();
+        assert_eq!(*pooled, Uniq(41));
+        assert_eq!(pool.take_events(), vec![
+            PoolEvent::Park(key.clone()),
+            PoolEvent::Put(key.clone()),
+            PoolEvent::CompleteParked(key),
+        ]);
     }
 
     #[test]
@@ -805,20 +3164,1163 @@ mod tests {
         }).wait().unwrap();
     }
 
-    #[derive(Debug)]
-    struct CanClose {
-        val: i32,
-        closed: bool,
+    #[test]
+    fn test_pool_dial_backoff_grows() {
+        let pool = Pool::<Uniq<i32>>::new(true, Some(Duration::from_secs(10)));
+        pool.inner.lock().unwrap().config.dial_backoff(Duration::from_millis(100), Duration::from_secs(1), false);
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        assert!(pool.dial_allowed_at(&key).is_none());
+
+        let mut delays = Vec::new();
+        for _ in 0..5 {
+            let before = Instant::now();
+            pool.connect_failed(&key);
+            let at = pool.dial_allowed_at(&key).expect("backing off");
+            delays.push(at - before);
+        }
+
+        // 50-100ms, 100-200ms, 200-400ms, 400-800ms, 500ms-1s
+        assert!(delays[0] >= Duration::from_millis(50));
+        assert!(delays[1] >= Duration::from_millis(100));
+        assert!(delays[2] >= Duration::from_millis(200));
+        assert!(delays[3] >= Duration::from_millis(400));
+        assert!(delays[4] >= Duration::from_millis(500));
+        assert!(delays[4] <= Duration::from_secs(1) + Duration::from_millis(50));
+
+        // success resets it
+        pool.pooled(c(key.clone()), Uniq(5));
+        assert!(pool.dial_allowed_at(&key).is_none());
     }
 
-    impl Poolable for CanClose {
-        fn is_closed(&self) -> bool {
-            self.closed
+    #[test]
+    fn test_pool_dial_backoff_fail_fast() {
+        future::lazy(|| {
+            let pool = Pool::<Uniq<i32>>::new(true, Some(Duration::from_secs(10)));
+            let key = (Arc::new("foo".to_string()), Ver::Http1);
+            pool.connect_failed(&key);
+            assert!(pool.checkout(key.clone()).poll().unwrap().is_not_ready());
+
+            pool.inner.lock().unwrap().config.dial_backoff(Duration::from_secs(1), Duration::from_secs(1), true);
+            pool.connect_failed(&key);
+            assert_eq!(pool.checkout(key).poll().unwrap_err(), CheckoutError::BackingOff);
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_dial_backoff_connecting() {
+        future::lazy(|| {
+            let pool = Pool::<Uniq<i32>>::new(true, Some(Duration::from_secs(10)));
+            pool.inner.lock().unwrap().config.dial_backoff(Duration::from_millis(40), Duration::from_millis(40), false);
+            let key = (Arc::new("foo".to_string()), Ver::Http1);
+            let h2_key = (Arc::new("foo".to_string()), Ver::Http2);
+            pool.connect_failed(&key);
+            pool.connect_failed(&h2_key);
+            assert!(pool.connecting(&key).is_none());
+            assert!(pool.connecting(&h2_key).is_none());
+
+            // A checkout waiting during the backoff wakes up once it ends,
+            // so whatever it races can dial.
+            let mut checkout = pool.checkout(key.clone());
+            assert!(checkout.poll().unwrap().is_not_ready());
+            assert!(checkout.backoff_timer.is_some());
+            ::std::thread::sleep(Duration::from_millis(50));
+            assert!(checkout.poll().unwrap().is_not_ready());
+            assert!(checkout.backoff_timer.is_none());
+            assert!(pool.connecting(&key).is_some());
+            assert!(pool.connecting(&h2_key).is_some());
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_stats_dials_coalesced() {
+        let pool = Pool::<Share<i32>>::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http2);
+
+        let connecting = pool.connecting(&key).expect("first dial");
+        assert!(pool.connecting(&key).is_none());
+        assert!(pool.connecting(&key).is_none());
+
+        let stats = pool.stats();
+        assert_eq!(stats.dials_started, 1);
+        assert_eq!(stats.dials_coalesced, 2);
+
+        drop(connecting);
+        let _connecting = pool.connecting(&key).expect("second dial");
+        assert_eq!(pool.stats().dials_started, 2);
+
+        // HTTP/1 dials are never coalesced, and so aren't counted
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        let _connecting = pool.connecting(&key).expect("http1 dial");
+        assert_eq!(pool.stats().dials_started, 2);
+    }
+
+    #[test]
+    fn test_pool_shrink_to_fit() {
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        for i in 0..64 {
+            pool.pooled(c(key.clone()), Uniq(i));
         }
+        let taken = (0..60)
+            .map(|_| pool.take(&key, &Wants::default()).expect("idle"))
+            .collect::<Vec<_>>();
 
-        fn reserve(self) -> Reservation<Self> {
-            Reservation::Unique(self)
+        let cap = pool.inner.lock().unwrap().idle.get(&key).unwrap().capacity();
+        assert!(cap >= 64);
+
+        // The idle interval gives back what the list no longer needs.
+        pool.poll_expired(Instant::now());
+        {
+            let inner = pool.inner.lock().unwrap();
+            let list = inner.idle.get(&key).unwrap();
+            assert_eq!(list.len(), 4);
+            assert!(list.capacity() < cap);
+        }
+
+        drop(taken);
+        assert_eq!(pool.inner.lock().unwrap().idle.get(&key).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_pool_checkout_max_park_duration_rechecks_idle() {
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        pool.inner.lock().unwrap().config.max_park_duration(Some(Duration::from_millis(50)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        let mut checkout = pool.checkout(key.clone());
+        let checkout = future::lazy(move || {
+            assert!(checkout.poll().unwrap().is_not_ready());
+            Ok::<_, ()>(checkout)
+        }).wait().unwrap();
+
+        // Simulate a connection becoming idle without notifying the parked
+        // checkout.
+        pool.inner.lock().unwrap().idle.entry(key.clone())
+            .or_insert(Vec::new())
+            .push(Idle {
+                idle_at: Instant::now(),
+                meta: Meta::new(),
+                quarantined_at: None,
+                value: Uniq(41),
+            });
+
+        assert_eq!(*checkout.wait().unwrap(), Uniq(41));
+    }
+
+    #[test]
+    fn test_pool_checkout_max_parked_per_host() {
+        future::lazy(|| {
+            let pool = Pool::<Uniq<i32>>::new(true, Some(Duration::from_secs(10)));
+            pool.inner.lock().unwrap().config.max_parked_per_host(Some(2));
+            let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+            let mut checkout1 = pool.checkout(key.clone());
+            let mut checkout2 = pool.checkout(key.clone());
+            let mut checkout3 = pool.checkout(key.clone());
+            assert!(checkout1.poll().unwrap().is_not_ready());
+            assert!(checkout2.poll().unwrap().is_not_ready());
+            assert_eq!(checkout3.poll().unwrap_err(), CheckoutError::Busy);
+            assert_eq!(pool.inner.lock().unwrap().parked.get(&key).unwrap().len(), 2);
+
+            // once one goes away, there is room again
+            drop(checkout1);
+            let mut checkout4 = pool.checkout(key.clone());
+            assert!(checkout4.poll().unwrap().is_not_ready());
+            assert_eq!(pool.inner.lock().unwrap().parked.get(&key).unwrap().len(), 2);
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_defer_http2_pooling() {
+        future::lazy(|| {
+            let pool = Pool::new(true, Some(Duration::from_secs(10)));
+            pool.inner.lock().unwrap().config.defer_http2_pooling(true);
+            let key = (Arc::new("foo".to_string()), Ver::Http2);
+
+            let pooled = pool.pooled(pool.connecting(&key).unwrap(), Share(41));
+            assert!(pool.inner.lock().unwrap().idle.get(&key).is_none());
+            // other dials still wait on this connection
+            assert!(pool.connecting(&key).is_none());
+            let mut checkout = pool.checkout(key.clone());
+            assert!(checkout.poll().unwrap().is_not_ready());
+
+            drop(pooled);
+            assert_eq!(pool.inner.lock().unwrap().idle.get(&key).map(|entries| entries.len()), Some(1));
+            assert!(pool.inner.lock().unwrap().connecting.is_empty());
+            match checkout.poll().unwrap() {
+                Async::Ready(pooled) => assert_eq!(*pooled, Share(41)),
+                _ => panic!("not ready"),
+            }
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_take_evicts_closed() {
+        fn fill(pool: &Pool<CanClose>, key: &Key) {
+            let mut inner = pool.inner.lock().unwrap();
+            inner.put(key.clone(), CanClose { val: 1, closed: false }, Meta::new());
+            inner.put(key.clone(), CanClose { val: 2, closed: true }, Meta::new());
+            inner.put(key.clone(), CanClose { val: 3, closed: true }, Meta::new());
+        }
+
+        fn evicted(pool: &Pool<CanClose>) -> Vec<PoolEvent> {
+            let mut inner = pool.inner.lock().unwrap();
+            let events = ::std::mem::replace(&mut inner.events, Vec::new());
+            events.into_iter()
+                .filter(|event| match *event {
+                    PoolEvent::Evict(..) => true,
+                    _ => false,
+                })
+                .collect()
         }
+
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        let closed = PoolEvent::Evict(key.clone(), EvictReason::Closed);
+
+        // by default, closed connections are skipped over
+        fill(&pool, &key);
+        assert_eq!(pool.take(&key, &Wants::default()).map(|p| p.val), Some(1));
+        assert_eq!(evicted(&pool), vec![closed.clone(), closed.clone()]);
+        // only the checked out connection was pooled again
+        assert_eq!(pool.inner.lock().unwrap().idle.get(&key).map(|list| list.len()), Some(1));
+        pool.inner.lock().unwrap().idle.clear();
+
+        // stopping early still removes each closed connection it finds
+        pool.inner.lock().unwrap().config.scan_past_closed(false);
+        fill(&pool, &key);
+        assert!(pool.take(&key, &Wants::default()).is_none());
+        assert_eq!(pool.inner.lock().unwrap().idle.get(&key).map(|list| list.len()), Some(2));
+        assert!(pool.take(&key, &Wants::default()).is_none());
+        assert_eq!(pool.take(&key, &Wants::default()).map(|p| p.val), Some(1));
+        assert_eq!(evicted(&pool), vec![closed.clone(), closed.clone()]);
+
+        // a checkout that stopped early goes on to dial instead of waiting
+        pool.inner.lock().unwrap().idle.clear();
+        fill(&pool, &key);
+        future::lazy(|| {
+            assert_eq!(pool.checkout(key.clone()).poll().unwrap_err(), CheckoutError::Canceled);
+            assert!(pool.inner.lock().unwrap().parked.get(&key).is_none());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_checkout_drop_unpolled_leaves_parked() {
+        future::lazy(|| {
+            let pool: Pool<Uniq<i32>> = Pool::new(true, Some(Duration::from_secs(10)));
+            let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+            let mut checkout1 = pool.checkout(key.clone());
+            assert!(checkout1.poll().unwrap().is_not_ready());
+            assert_eq!(pool.inner.lock().unwrap().parked.get(&key).map(|q| q.len()), Some(1));
+
+            drop(pool.checkout(key.clone()));
+            assert_eq!(pool.inner.lock().unwrap().parked.get(&key).map(|q| q.len()), Some(1));
+
+            drop(checkout1);
+            assert!(pool.inner.lock().unwrap().parked.get(&key).is_none());
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_connect_coalesce_window() {
+        let pool: Pool<Share<i32>> = Pool::new(true, None);
+        pool.inner.lock().unwrap().config.connect_coalesce_window(Some(Duration::from_millis(50)));
+        let key = (Arc::new("foo".to_string()), Ver::Http2);
+
+        assert!(pool.coalesce_window_ends_at(&key).is_none());
+        let slow = pool.connecting(&key).expect("first dial");
+        assert!(pool.connecting(&key).is_none());
+        let ends_at = pool.coalesce_window_ends_at(&key).expect("window");
+        assert!(ends_at > Instant::now());
+
+        ::std::thread::sleep(Duration::from_millis(100));
+        assert!(pool.coalesce_window_ends_at(&key).unwrap() <= Instant::now());
+        let second = pool.connecting(&key).expect("dial after window");
+        assert!(pool.connecting(&key).is_none());
+        // the window restarted with the second dial
+        assert!(pool.coalesce_window_ends_at(&key).unwrap() > ends_at);
+        assert_eq!(pool.stats().dials_started, 2);
+
+        // the slow dial failing doesn't end the other one
+        drop(slow);
+        assert!(pool.connecting(&key).is_none());
+
+        drop(second);
+        assert!(pool.inner.lock().unwrap().connecting.is_empty());
+    }
+
+    #[test]
+    fn test_pool_with_config() {
+        future::lazy(|| {
+            let mut config = PoolConfig::new();
+            config
+                .timeout(Some(Duration::from_secs(10)))
+                .max_parked_per_host(Some(1));
+            let pool = Pool::<Uniq<i32>>::with_config(config);
+            assert_eq!(pool.inner.lock().unwrap().config.timeout, Some(Duration::from_secs(10)));
+
+            let key = (Arc::new("foo".to_string()), Ver::Http1);
+            let mut checkout1 = pool.checkout(key.clone());
+            let mut checkout2 = pool.checkout(key.clone());
+            assert!(checkout1.poll().unwrap().is_not_ready());
+            assert_eq!(checkout2.poll().unwrap_err(), CheckoutError::Busy);
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_idle_order_freshest() {
+        fn fill(pool: &Pool<Uniq<i32>>, key: &Key) {
+            for &(val, secs) in &[(1, 30), (2, 60), (3, 10)] {
+                let mut pooled = pool.pooled(c(key.clone()), Uniq(val));
+                pooled.set_idle_timeout(Some(Duration::from_secs(secs)));
+            }
+        }
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        let lifo = Pool::new(true, Some(Duration::from_secs(90)));
+        fill(&lifo, &key);
+        assert_eq!(lifo.take(&key, &Wants::default()).map(|p| p.0), Some(3));
+
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(90)))
+            .idle_order(IdleOrder::Freshest);
+        let freshest = Pool::with_config(config);
+        fill(&freshest, &key);
+        let pooled = freshest.take(&key, &Wants::default()).expect("freshest");
+        assert_eq!(*pooled, Uniq(2));
+        // the rest keep their order
+        let inner = freshest.inner.lock().unwrap();
+        let rest = inner.idle.get(&key).unwrap().iter().map(|e| e.value.0).collect::<Vec<_>>();
+        assert_eq!(rest, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_pool_stats_closed_rejections() {
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        {
+            let mut inner = pool.inner.lock().unwrap();
+            for val in 0..4 {
+                inner.put(key.clone(), CanClose { val, closed: val % 2 == 1 }, Meta::new());
+            }
+        }
+        assert!(pool.stats().closed_rejections.is_empty());
+
+        assert_eq!(pool.take(&key, &Wants::default()).map(|p| p.val), Some(2));
+        assert_eq!(pool.stats().closed_rejections.get(&key), Some(&1));
+        // the returned connection is on top again, so nothing is skipped
+        assert_eq!(pool.take(&key, &Wants::default()).map(|p| p.val), Some(2));
+        assert_eq!(pool.stats().closed_rejections.get(&key), Some(&1));
+    }
+
+    #[test]
+    fn test_pool_take_summarizes_removed() {
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        for _ in 0..2 {
+            {
+                let mut inner = pool.inner.lock().unwrap();
+                inner.put(key.clone(), CanClose { val: 0, closed: true }, Meta::new());
+                inner.put(key.clone(), CanClose { val: 1, closed: true }, Meta::new());
+            }
+            assert!(pool.take(&key, &Wants::default()).is_none());
+        }
+        {
+            let inner = pool.inner.lock().unwrap();
+            let log = inner.removed_log.get(&key).expect("removed log");
+            assert_eq!(log.closed, 4);
+            assert_eq!(log.expired, 0);
+        }
+
+        // the summary is logged once the pool is dropped
+        REMOVED_LOGGED.with(|logged| logged.borrow_mut().clear());
+        drop(pool);
+        REMOVED_LOGGED.with(|logged| {
+            assert_eq!(*logged.borrow(), vec![("foo".to_string(), 4, 0)]);
+        });
+
+        // or a second later, with whatever the pool does next, even for
+        // another key
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        pool.inner.lock().unwrap().put(key.clone(), CanClose { val: 0, closed: true }, Meta::new());
+        assert!(pool.take(&key, &Wants::default()).is_none());
+        pool.inner.lock().unwrap().removed_log.get_mut(&key).unwrap().since -= Duration::from_secs(1);
+        REMOVED_LOGGED.with(|logged| logged.borrow_mut().clear());
+        let other = (Arc::new("bar".to_string()), Ver::Http1);
+        drop(pool.pooled(c(other), CanClose { val: 1, closed: false }));
+        assert!(pool.inner.lock().unwrap().removed_log.is_empty());
+        REMOVED_LOGGED.with(|logged| {
+            assert_eq!(*logged.borrow(), vec![("foo".to_string(), 1, 0)]);
+        });
+
+        // when tracing each removal, nothing is summarized
+        let mut config = PoolConfig::new();
+        config.trace_each_removal(true);
+        let pool = Pool::with_config(config);
+        pool.inner.lock().unwrap().put(key.clone(), CanClose { val: 0, closed: true }, Meta::new());
+        assert!(pool.take(&key, &Wants::default()).is_none());
+        assert!(pool.inner.lock().unwrap().removed_log.is_empty());
+    }
+
+    #[test]
+    fn test_pool_poll_expired() {
+        future::lazy(|| {
+            let pool = Pool::new(true, Some(Duration::from_secs(10)));
+            let foo = (Arc::new("foo".to_string()), Ver::Http1);
+            let bar = (Arc::new("bar".to_string()), Ver::Http1);
+            let waiting = (Arc::new("waiting".to_string()), Ver::Http1);
+
+            for i in 0..3 {
+                drop(pool.pooled(c(foo.clone()), Uniq(i)));
+            }
+            drop(pool.pooled(c(bar.clone()), Uniq(3)));
+            let mut checkout = pool.checkout(waiting.clone());
+            assert!(checkout.poll().unwrap().is_not_ready());
+            // Canceled without the checkout's drop cleaning up.
+            checkout.parked.take();
+            drop(checkout);
+            assert_eq!(pool.inner.lock().unwrap().parked.get(&waiting).unwrap().len(), 1);
+
+            let now = Instant::now();
+            assert_eq!(pool.poll_expired(now), 0);
+            assert_eq!(pool.inner.lock().unwrap().idle.len(), 2);
+            assert!(pool.inner.lock().unwrap().parked.is_empty());
+
+            assert_eq!(pool.poll_expired(now + Duration::from_secs(11)), 4);
+            assert!(pool.inner.lock().unwrap().idle.is_empty());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_poll_expired_without_timeout() {
+        let pool = Pool::new(true, None);
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        drop(pool.pooled(c(key.clone()), Uniq(1)));
+
+        assert_eq!(pool.poll_expired(Instant::now() + Duration::from_secs(60 * 60)), 0);
+        assert_eq!(pool.inner.lock().unwrap().idle.get(&key).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_pool_max_idle_per_host() {
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(10)))
+            .max_idle_per_host(Some(2));
+        let pool = Pool::with_config(config);
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        let idle = |pool: &Pool<Uniq<i32>>| {
+            pool.inner.lock().unwrap().idle.get(&key).map(|list| {
+                list.iter().map(|e| e.value.0).collect::<Vec<_>>()
+            }).unwrap_or(Vec::new())
+        };
+
+        let burst = (0..5).map(|i| pool.pooled(c(key.clone()), Uniq(i))).collect::<Vec<_>>();
+        drop(burst);
+        // The connections idled first are kept.
+        assert_eq!(idle(&pool), vec![0, 1]);
+
+        for _ in 0..3 {
+            let taken = pool.take(&key, &Wants::default()).unwrap();
+            let new = pool.pooled(c(key.clone()), Uniq(9));
+            drop(new);
+            assert_eq!(idle(&pool).len(), 2);
+            drop(taken);
+            assert_eq!(idle(&pool).len(), 2);
+        }
+
+        // HTTP/2 handles are one connection.
+        let key = (Arc::new("foo".to_string()), Ver::Http2);
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(10)))
+            .max_idle_per_host(Some(1));
+        let pool = Pool::with_config(config);
+        let connecting = pool.connecting(&key).expect("connecting");
+        let first = pool.pooled(connecting, Share(1));
+        let handles = (0..3).map(|_| pool.take(&key, &Wants::default()).unwrap()).collect::<Vec<_>>();
+        drop(handles);
+        drop(first);
+        assert_eq!(pool.inner.lock().unwrap().idle.get(&key).unwrap().len(), 1);
+        assert!(pool.take(&key, &Wants::default()).is_some());
+    }
+
+    #[test]
+    fn test_pool_max_idle_per_host_unlimited() {
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        let burst = (0..5).map(|i| pool.pooled(c(key.clone()), Uniq(i))).collect::<Vec<_>>();
+        drop(burst);
+        assert_eq!(pool.inner.lock().unwrap().idle.get(&key).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_pool_max_idle_per_host_ignores_unusable() {
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(10)))
+            .max_idle_per_host(Some(1));
+        let pool = Pool::with_config(config);
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        let idle = |pool: &Pool<CanClose>| {
+            pool.inner.lock().unwrap().idle.get(&key).map(|list| {
+                list.iter().map(|e| e.value.val).collect::<Vec<_>>()
+            }).unwrap_or(Vec::new())
+        };
+
+        // A closed connection is evicted to make room.
+        drop(pool.pooled(c(key.clone()), CanClose { val: 1, closed: false }));
+        pool.inner.lock().unwrap().idle.get_mut(&key).unwrap()[0].value.closed = true;
+        drop(pool.pooled(c(key.clone()), CanClose { val: 2, closed: false }));
+        assert_eq!(idle(&pool), vec![2]);
+        assert!(pool.inner.lock().unwrap().events.contains(&PoolEvent::Evict(key.clone(), EvictReason::Closed)));
+
+        // So is an expired one.
+        pool.inner.lock().unwrap().idle.get_mut(&key).unwrap()[0].idle_at -= Duration::from_secs(60);
+        drop(pool.pooled(c(key.clone()), CanClose { val: 3, closed: false }));
+        assert_eq!(idle(&pool), vec![3]);
+
+        // A quarantined one doesn't count against the limit.
+        let mut quarantined = pool.take(&key, &Wants::default()).unwrap();
+        quarantined.quarantine();
+        drop(quarantined);
+        drop(pool.pooled(c(key.clone()), CanClose { val: 4, closed: false }));
+        assert_eq!(idle(&pool), vec![3, 4]);
+
+        // But a usable one still does.
+        drop(pool.pooled(c(key.clone()), CanClose { val: 5, closed: false }));
+        assert_eq!(idle(&pool), vec![3, 4]);
+    }
+
+    #[derive(Debug)]
+    struct CanClose {
+        val: i32,
+        closed: bool,
+    }
+
+    impl Poolable for CanClose {
+        fn is_closed(&self) -> bool {
+            self.closed
+        }
+
+        fn reserve(self) -> Reservation<Self> {
+            Reservation::Unique(self)
+        }
+    }
+
+    #[test]
+    fn test_pool_metrics_text() {
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(10)))
+            .metrics_top_keys(1);
+        let pool = Pool::with_config(config);
+        let busy = (Arc::new("http://busy".to_string()), Ver::Http1);
+        let quiet = (Arc::new("http://\"quiet\"".to_string()), Ver::Http1);
+
+        drop(pool.pooled(c(busy.clone()), Uniq(1)));
+        drop(pool.pooled(c(busy.clone()), Uniq(2)));
+        drop(pool.pooled(c(busy.clone()), Uniq(3)));
+        drop(pool.take(&busy, &Wants::default()).unwrap());
+        drop(pool.take(&busy, &Wants::default()).unwrap());
+        drop(pool.pooled(c(quiet.clone()), Uniq(4)));
+        let checked_out = pool.take(&quiet, &Wants::default()).unwrap();
+
+        let text = pool.metrics_text("hyper_pool");
+        assert!(text.contains("# TYPE hyper_pool_idle_connections gauge\nhyper_pool_idle_connections 3\n"));
+        assert!(text.contains("hyper_pool_parked_checkouts 0\n"));
+        assert!(text.contains("hyper_pool_checked_out_connections 1\n"));
+        assert!(text.contains("# TYPE hyper_pool_closed_rejections_total counter\n"));
+        assert!(text.contains("# HELP hyper_pool_dials_in_flight_peak "));
+        assert!(text.contains(
+            "hyper_pool_idle_connections_by_key{host=\"http://busy\",version=\"http1\"} 3\n"
+        ));
+        // Only the most active key is listed, the rest are summed.
+        assert!(!text.contains("quiet"));
+        let other = "{host=\"other\",version=\"other\"}";
+        assert!(text.contains(&format!("hyper_pool_idle_connections_by_key{} 0\n", other)));
+        assert!(text.contains(&format!("hyper_pool_closed_rejections_total{} 0\n", other)));
+        // A listed key has a line even without a value.
+        assert!(text.contains(
+            "hyper_pool_closed_rejections_total{host=\"http://busy\",version=\"http1\"} 0\n"
+        ));
+
+        // The listed key stays listed once another is busier.
+        drop(checked_out);
+        for _ in 0..10 {
+            drop(pool.take(&quiet, &Wants::default()).unwrap());
+        }
+        let text = pool.metrics_text("hyper_pool");
+        assert!(!text.contains("quiet"));
+        assert!(text.contains(&format!("hyper_pool_idle_connections_by_key{} 1\n", other)));
+
+        let mut config = PoolConfig::new();
+        config.timeout(Some(Duration::from_secs(10)));
+        let pool = Pool::with_config(config);
+        drop(pool.pooled(c(quiet.clone()), Uniq(3)));
+        let text = pool.metrics_text("hyper_pool");
+        assert!(text.contains("host=\"http://\\\"quiet\\\"\",version=\"http1\"} 1\n"));
+
+        // Ties between equally active keys are broken by the key.
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(10)))
+            .metrics_top_keys(1);
+        let pool = Pool::with_config(config);
+        let a = (Arc::new("http://a".to_string()), Ver::Http1);
+        let b = (Arc::new("http://b".to_string()), Ver::Http1);
+        drop(pool.pooled(c(b.clone()), Uniq(1)));
+        drop(pool.pooled(c(a.clone()), Uniq(2)));
+        let text = pool.metrics_text("hyper_pool");
+        assert!(text.contains("host=\"http://a\""));
+        assert!(!text.contains("host=\"http://b\""));
+    }
+
+    #[test]
+    fn test_pool_non_idempotent_cooldown() {
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(10)))
+            .non_idempotent_cooldown(Some(Duration::from_millis(100)));
+        let pool = Pool::with_config(config);
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        let mut post = pool.pooled(c(key.clone()), Uniq(1));
+        post.set_last_idempotent(false);
+        let mut get = pool.pooled(c(key.clone()), Uniq(2));
+        get.set_last_idempotent(true);
+        drop(get);
+        drop(post);
+
+        // The most recently idle is skipped while it cools down.
+        let pooled = pool.take(&key, &Wants::default()).unwrap();
+        assert_eq!(*pooled, Uniq(2));
+        assert!(pool.take(&key, &Wants::default()).is_none());
+        drop(pooled);
+
+        ::std::thread::sleep(Duration::from_millis(100));
+        let pooled = pool.take(&key, &Wants::default()).unwrap();
+        assert_eq!(*pooled, Uniq(2));
+        let cooled = pool.take(&key, &Wants::default()).unwrap();
+        assert_eq!(*cooled, Uniq(1));
+
+        // Checking it out again starts over, so it isn't cooling down
+        // unless tagged again.
+        drop(cooled);
+        let pooled = pool.take(&key, &Wants::default()).unwrap();
+        assert_eq!(*pooled, Uniq(1));
+    }
+
+    #[test]
+    fn test_pool_non_idempotent_cooldown_wakes_parked() {
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(10)))
+            .non_idempotent_cooldown(Some(Duration::from_millis(50)));
+        let pool = Pool::with_config(config);
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        let mut checkout = pool.checkout(key.clone());
+        assert!(future::lazy(|| checkout.poll()).wait().unwrap().is_not_ready());
+
+        // Cooling down, so it isn't given to the parked checkout now...
+        let cooled_at = Instant::now() + Duration::from_millis(50);
+        let mut post = pool.pooled(c(key.clone()), Uniq(1));
+        post.set_last_idempotent(false);
+        drop(post);
+        assert!(pool.inner.lock().unwrap().parked.get(&key).is_none());
+
+        // ...but the checkout gets it once the cooldown is over.
+        let pooled = checkout.wait().unwrap();
+        assert_eq!(*pooled, Uniq(1));
+        assert!(Instant::now() >= cooled_at);
+    }
+
+    #[test]
+    fn test_pool_http2_least_loaded() {
+        future::lazy(|| {
+            let mut config = PoolConfig::new();
+            config
+                .timeout(Some(Duration::from_secs(10)))
+                .http2_max_streams(Some(10));
+            let pool = Pool::with_config(config);
+            let key = (Arc::new("foo".to_string()), Ver::Http2);
+            let checkout = || pool.checkout(key.clone()).wait().unwrap();
+
+            let first = pool.pooled(pool.connecting(&key).expect("first"), Share(1));
+            let on_first = checkout();
+            assert_eq!(*on_first, Share(1));
+
+            // Both connections are kept, and the new one has fewer streams.
+            let second = pool.pooled(pool.connecting(&key).expect("second"), Share(2));
+            assert_eq!(pool.inner.lock().unwrap().idle.get(&key).map(|list| list.len()), Some(2));
+            let mut on_second = vec![checkout(), checkout()];
+            assert_eq!(*on_second[0], Share(2));
+            assert_eq!(*on_second[1], Share(2));
+
+            // 2 streams on the first, 3 on the second.
+            assert_eq!(*checkout(), Share(1));
+            on_second.pop();
+            on_second.pop();
+            let on_second = checkout();
+            assert_eq!(*on_second, Share(2));
+
+            drop((first, second, on_first, on_second));
+            assert!(pool.inner.lock().unwrap().streams.is_empty());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_http2_saturated_parks() {
+        future::lazy(|| {
+            let mut config = PoolConfig::new();
+            config
+                .timeout(Some(Duration::from_secs(10)))
+                .http2_max_streams(Some(2));
+            let pool = Pool::with_config(config);
+            let key = (Arc::new("foo".to_string()), Ver::Http2);
+
+            let dialer = pool.pooled(pool.connecting(&key).expect("connecting"), Share(1));
+            let second = pool.checkout(key.clone()).wait().unwrap();
+            assert_eq!(*second, Share(1));
+
+            // Both streams are in use, so the checkout waits for one
+            // instead of another dial.
+            let mut checkout = pool.checkout(key.clone());
+            assert!(checkout.poll().unwrap().is_not_ready());
+            assert_eq!(checkout.park_reason, Some(ParkReason::Saturated));
+            assert!(pool.connecting(&key).is_none());
+
+            drop(second);
+            let pooled = match checkout.poll().unwrap() {
+                Async::Ready(pooled) => pooled,
+                Async::NotReady => panic!("checkout should be woken"),
+            };
+            assert_eq!(*pooled, Share(1));
+            assert_eq!(pool.inner.lock().unwrap().streams.values().next().unwrap().in_use, 2);
+
+            // Without an idle connection, the reason is different.
+            let other = (Arc::new("bar".to_string()), Ver::Http2);
+            let mut checkout = pool.checkout(other.clone());
+            assert!(checkout.poll().unwrap().is_not_ready());
+            assert_eq!(checkout.park_reason, Some(ParkReason::NoConnection));
+
+            drop((dialer, pooled));
+            assert!(pool.connecting(&key).is_some());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_key_ignores_default_port() {
+        future::lazy(|| {
+            let pool = Pool::new(true, Some(Duration::from_secs(10)));
+            let explicit = (Arc::new("https://example.com:443".to_string()), Ver::Http1);
+            let implicit = (Arc::new("https://example.com".to_string()), Ver::Http1);
+            let other = (Arc::new("https://example.com:8443".to_string()), Ver::Http1);
+
+            let connecting = pool.connecting(&explicit).expect("connecting");
+            drop(pool.pooled(connecting, Uniq(41)));
+
+            assert!(pool.checkout(other).poll().unwrap().is_not_ready());
+            let pooled = match pool.checkout(implicit).poll().unwrap() {
+                Async::Ready(pooled) => pooled,
+                _ => panic!("not ready"),
+            };
+            assert_eq!(*pooled, Uniq(41));
+            drop(pooled);
+
+            // And the other way around.
+            let http = (Arc::new("http://example.com".to_string()), Ver::Http1);
+            let connecting = pool.connecting(&http).expect("connecting");
+            drop(pool.pooled(connecting, Uniq(80)));
+            let explicit = (Arc::new("http://example.com:80".to_string()), Ver::Http1);
+            match pool.checkout(explicit).poll().unwrap() {
+                Async::Ready(pooled) => assert_eq!(*pooled, Uniq(80)),
+                _ => panic!("not ready"),
+            }
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_connect_failed_ignores_default_port() {
+        let pool = Pool::<Uniq<i32>>::new(true, Some(Duration::from_secs(10)));
+        let explicit = (Arc::new("https://example.com:443".to_string()), Ver::Http1);
+        let implicit = (Arc::new("https://example.com".to_string()), Ver::Http1);
+        pool.connect_failed(&explicit);
+        assert!(pool.dial_allowed_at(&implicit).is_some());
+    }
+
+    #[test]
+    fn test_pool_events() {
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        drop(pool.pooled(c(key.clone()), CanClose { val: 1, closed: false }));
+        drop(pool.pooled(c(key.clone()), CanClose { val: 2, closed: false }));
+        assert_eq!(pool.take_events(), vec![
+            PoolEvent::Put(key.clone()),
+            PoolEvent::Put(key.clone()),
+        ]);
+
+        pool.inner.lock().unwrap().idle.get_mut(&key).unwrap()[1].value.closed = true;
+        let pooled = pool.take(&key, &Wants::default()).unwrap();
+        assert_eq!(pooled.val, 1);
+        assert_eq!(pool.take_events(), vec![
+            PoolEvent::Evict(key.clone(), EvictReason::Closed),
+            PoolEvent::Take(key),
+        ]);
+        // Drained, so nothing is reported twice.
+        assert_eq!(pool.take_events(), vec![]);
+    }
+
+    #[test]
+    fn test_pool_per_connection_idle_timeout() {
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        let mut pooled = pool.pooled(c(key.clone()), Uniq(60));
+        pooled.set_idle_timeout(Some(Duration::from_secs(60)));
+        drop(pooled);
+        drop(pool.pooled(c(key.clone()), Uniq(10)));
+        let mut pooled = pool.pooled(c(key.clone()), Uniq(1));
+        pooled.set_idle_timeout(Some(Duration::from_secs(1)));
+        drop(pooled);
+
+        let age = |pool: &Pool<Uniq<i32>>, secs| {
+            for entry in pool.inner.lock().unwrap().idle.get_mut(&key).unwrap() {
+                entry.idle_at -= Duration::from_secs(secs);
+            }
+        };
+        let idle = |pool: &Pool<Uniq<i32>>| {
+            pool.inner.lock().unwrap().idle.values().map(|list| list.len()).sum::<usize>()
+        };
+
+        age(&pool, 2);
+        pool.inner.lock().unwrap().clear_expired(Instant::now());
+        assert_eq!(idle(&pool), 2);
+
+        age(&pool, 10);
+        pool.inner.lock().unwrap().clear_expired(Instant::now());
+        assert_eq!(idle(&pool), 1);
+        let pooled = pool.take(&key, &Wants::default()).unwrap();
+        assert_eq!(*pooled, Uniq(60));
+    }
+
+    #[test]
+    fn test_pool_outstanding_count() {
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(10)))
+            .leak_warning(Some(Duration::from_secs(0)));
+        let pool = Pool::with_config(config);
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        assert_eq!(pool.outstanding_count(), 0);
+
+        drop(pool.pooled(c(key.clone()), Uniq(41)));
+        drop(pool.pooled(c(key.clone()), Uniq(42)));
+        let leaked = pool.take(&key, &Wants::default()).unwrap();
+        let returned = pool.take(&key, &Wants::default()).unwrap();
+        assert_eq!(pool.outstanding_count(), 2);
+
+        drop(returned);
+        ::std::mem::forget(leaked);
+        assert_eq!(pool.outstanding_count(), 1);
+
+        ::std::thread::sleep(Duration::from_millis(10));
+        let mut inner = pool.inner.lock().unwrap();
+        inner.clear_expired(Instant::now());
+        assert_eq!(inner.busy_warned.len(), 1);
+    }
+
+    #[test]
+    fn test_pool_idle_order_weighted_random() {
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(100)))
+            .idle_order(IdleOrder::WeightedRandom);
+        let pool = Pool::with_config(config);
+        // xorshift64, seeded
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        pool.set_random(move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        });
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        for secs in 1..5 {
+            let mut pooled = pool.pooled(c(key.clone()), Uniq(secs));
+            pooled.set_idle_timeout(Some(Duration::from_secs(secs * 10)));
+        }
+
+        let mut picks = [0; 4];
+        for _ in 0..10_000 {
+            let pooled = pool.take(&key, &Wants::default()).unwrap();
+            picks[pooled.0 as usize - 1] += 1;
+        }
+        // weighted 1:2:3:4
+        for (i, &count) in picks.iter().enumerate() {
+            let expected = 1000 * (i + 1);
+            assert!(count > expected - 250 && count < expected + 250, "picks = {:?}", picks);
+        }
+    }
+
+    #[test]
+    fn test_pool_checkout_error_canceled() {
+        future::lazy(|| {
+            let pool = Pool::<Share<i32>>::new(true, Some(Duration::from_secs(10)));
+            let key = (Arc::new("foo".to_string()), Ver::Http2);
+            let connecting = pool.connecting(&key).unwrap();
+            let mut checkout = pool.checkout(key);
+            assert!(checkout.poll().unwrap().is_not_ready());
+
+            // the dial failed, so nothing is coming for the checkout
+            drop(connecting);
+            let err = checkout.poll().unwrap_err();
+            assert_eq!(err, CheckoutError::Canceled);
+            assert!(::Error::from(err).is_canceled());
+
+            assert!(!::Error::from(CheckoutError::BackingOff).is_canceled());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_error_threshold() {
+        let mut config = PoolConfig::new();
+        config
+            .timeout(Some(Duration::from_secs(10)))
+            .error_threshold(Some(2));
+        let pool = Pool::with_config(config);
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        let mut pooled = pool.pooled(c(key.clone()), Uniq(41));
+        pooled.record_error();
+        drop(pooled);
+
+        // errors are kept across checkouts
+        let mut pooled = pool.take(&key, &Wants::default()).expect("below threshold");
+        pooled.record_error();
+        drop(pooled);
+        assert!(pool.take(&key, &Wants::default()).is_none());
+    }
+
+    #[test]
+    fn test_pool_dials_in_flight() {
+        let pool = Pool::<Uniq<i32>>::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        let current = |key: &Key| pool.stats().dials_in_flight.get(key).map(|counts| counts.current);
+
+        let dials = (0..3).map(|_| pool.connecting(&key).unwrap()).collect::<Vec<_>>();
+        assert_eq!(current(&key), Some(3));
+
+        let mut dials = dials.into_iter();
+        let pooled = pool.pooled(dials.next().unwrap(), Uniq(41));
+        assert_eq!(current(&key), Some(2));
+        // a failed dial
+        drop(dials.next());
+        assert_eq!(current(&key), Some(1));
+        drop(dials);
+        drop(pooled);
+        assert_eq!(current(&key), Some(0));
+
+        // HTTP/2 dials coalesce, so only one is counted
+        let h2_key = (Arc::new("foo".to_string()), Ver::Http2);
+        let connecting = pool.connecting(&h2_key).unwrap();
+        assert!(pool.connecting(&h2_key).is_none());
+        assert_eq!(current(&h2_key), Some(1));
+        drop(connecting);
+
+        let stats = pool.stats();
+        assert_eq!(stats.dials_in_flight[&key], DialCounts { current: 0, peak: 3 });
+        assert_eq!(stats.dials_in_flight[&h2_key], DialCounts { current: 0, peak: 1 });
+    }
+
+    #[test]
+    fn test_pool_take_reuse_expired() {
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+        let expired_pool = |reuse_expired| {
+            let mut config = PoolConfig::new();
+            config
+                .timeout(Some(Duration::from_secs(10)))
+                .reuse_expired(reuse_expired);
+            let pool = Pool::with_config(config);
+            for i in 0..3 {
+                drop(pool.pooled(c(key.clone()), Uniq(i)));
+            }
+            for entry in pool.inner.lock().unwrap().idle.get_mut(&key).unwrap() {
+                entry.idle_at -= Duration::from_secs(11);
+            }
+            pool
+        };
+
+        let pool = expired_pool(false);
+        assert!(pool.take(&key, &Wants::default()).is_none());
+
+        let pool = expired_pool(true);
+        let pooled = pool.take(&key, &Wants::default()).expect("expired fallback");
+        assert_eq!(*pooled, Uniq(2));
+        assert!(pool.inner.lock().unwrap().idle.get(&key).is_none());
+
+        // a connection that isn't expired is still preferred
+        drop(pooled);
+        drop(pool.pooled(c(key.clone()), Uniq(3)));
+        for entry in pool.inner.lock().unwrap().idle.get_mut(&key).unwrap() {
+            if entry.value == Uniq(3) {
+                entry.idle_at -= Duration::from_secs(11);
+            }
+        }
+        let pooled = pool.take(&key, &Wants::default()).unwrap();
+        assert_eq!(*pooled, Uniq(2));
+        assert!(pool.inner.lock().unwrap().idle.get(&key).is_none());
+    }
+
+    #[test]
+    fn test_pool_reused() {
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+        let pooled = pool.pooled(c(key.clone()), Uniq(41));
+        assert!(!pooled.is_reused());
+        drop(pooled);
+
+        let pooled = pool.take(&key, &Wants::default()).unwrap();
+        assert!(pooled.is_reused());
+        drop(pooled);
+
+        // a new HTTP/2 connection is pooled before serving anything
+        let pool = Pool::new(true, Some(Duration::from_secs(10)));
+        let key = (Arc::new("foo".to_string()), Ver::Http2);
+        let first = pool.pooled(pool.connecting(&key).unwrap(), Share(41));
+        let second = pool.take(&key, &Wants::default()).unwrap();
+        assert!(!first.is_reused());
+        assert!(second.is_reused());
+    }
+
+    #[test]
+    fn test_pool_checkout_rate_limit() {
+        future::lazy(|| {
+            let mut config = PoolConfig::new();
+            config
+                .timeout(Some(Duration::from_secs(10)))
+                .checkout_rate_limit(Some(20), 2);
+            let pool = Pool::with_config(config);
+            let key = (Arc::new("foo".to_string()), Ver::Http1);
+            for i in 0..3 {
+                drop(pool.pooled(c(key.clone()), Uniq(i)));
+            }
+
+            let first = pool.checkout(key.clone()).poll().unwrap();
+            let second = pool.checkout(key.clone()).poll().unwrap();
+            assert!(first.is_ready());
+            assert!(second.is_ready());
+
+            // out of tokens, even with a connection idle
+            let mut checkout = pool.checkout(key.clone());
+            assert!(checkout.poll().unwrap().is_not_ready());
+            assert_eq!(pool.inner.lock().unwrap().idle.get(&key).map(|list| list.len()), Some(1));
+            assert!(pool.inner.lock().unwrap().parked.get(&key).is_none());
+
+            ::std::thread::sleep(Duration::from_millis(60));
+            assert!(checkout.poll().unwrap().is_ready());
+
+            // other keys have their own tokens
+            let other = (Arc::new("bar".to_string()), Ver::Http1);
+            drop(pool.pooled(c(other.clone()), Uniq(3)));
+            assert!(pool.checkout(other.clone()).poll().unwrap().is_ready());
+
+            // Buckets that filled back up are forgotten by the reaper.
+            {
+                let mut inner = pool.inner.lock().unwrap();
+                let now = Instant::now();
+                inner.clear_expired(now + Duration::from_secs(1));
+                assert!(inner.checkout_tokens.is_empty());
+            }
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_checkout_started() {
+        future::lazy(|| {
+            let mut config = PoolConfig::new();
+            config
+                .timeout(Some(Duration::from_secs(10)))
+                .checkout_rate_limit(Some(20), 1);
+            let pool = Pool::<Uniq<i32>>::with_config(config);
+            let key = (Arc::new("foo".to_string()), Ver::Http1);
+
+            let checkout = pool.checkout(key.clone()).started().poll().unwrap();
+            assert!(checkout.is_ready());
+
+            // Whatever races the next checkout has to wait its turn too.
+            let mut started = pool.checkout(key.clone()).started();
+            assert!(started.poll().unwrap().is_not_ready());
+            ::std::thread::sleep(Duration::from_millis(60));
+            let mut checkout = match started.poll().unwrap() {
+                Async::Ready(checkout) => checkout,
+                Async::NotReady => panic!("checkout should have started"),
+            };
+            // It doesn't need another token to look for a connection.
+            assert!(checkout.poll().unwrap().is_not_ready());
+            assert!(pool.inner.lock().unwrap().parked.get(&key).is_some());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn test_pool_checkout_parked_entry_died() {
+        future::lazy(|| {
+            let pool = Pool::new(true, Some(Duration::from_secs(10)));
+            let key = (Arc::new("localhost:12345".to_string()), Ver::Http1);
+
+            let mut checkout = pool.checkout(key.clone());
+            assert!(checkout.poll().unwrap().is_not_ready());
+
+            pool.inner.lock().unwrap().put(key.clone(), CanClose {
+                val: 1,
+                closed: true,
+            }, Meta::new());
+            assert!(pool.inner.lock().unwrap().parked.get(&key).is_none());
+
+            // the dead connection is discarded, and the checkout parks again
+            assert!(checkout.poll().unwrap().is_not_ready());
+            assert_eq!(pool.stats().parked_entries_died, 1);
+            assert_eq!(pool.inner.lock().unwrap().parked.get(&key).unwrap().len(), 1);
+
+            pool.inner.lock().unwrap().put(key.clone(), CanClose {
+                val: 2,
+                closed: false,
+            }, Meta::new());
+            match checkout.poll().unwrap() {
+                Async::Ready(pooled) => assert_eq!(pooled.val, 2),
+                _ => panic!("not ready"),
+            }
+            assert_eq!(pool.stats().parked_entries_died, 1);
+
+            Ok::<(), ()>(())
+        }).wait().unwrap();
     }
 
     #[test]
@@ -1,9 +1,10 @@
 extern crate pretty_env_logger;
 
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use futures::Async;
+use futures::{Async, Stream};
 use futures::future::poll_fn;
 use tokio::executor::thread_pool::{Builder as ThreadPoolBuilder};
 
@@ -112,3 +113,231 @@ fn conn_reset_after_write() {
         other => panic!("expected Incomplete, found {:?}", other)
     }
 }
+
+struct RefusingConnector;
+
+impl Connect for RefusingConnector {
+    type Transport = ::mock::Duplex;
+    type Error = io::Error;
+    type Future = future::FutureResult<(Self::Transport, connect::Connected), Self::Error>;
+
+    fn connect(&self, _dst: Destination) -> Self::Future {
+        future::err(io::Error::new(io::ErrorKind::ConnectionRefused, "mock refused"))
+    }
+}
+
+#[test]
+fn connect_error_backs_off_dials() {
+    let _ = pretty_env_logger::try_init();
+
+    let executor = ThreadPoolBuilder::new().pool_size(1).build();
+    let client = Client::builder()
+        .executor(executor.sender().clone())
+        .build::<_, ::Body>(RefusingConnector);
+
+    let req = Request::builder()
+        .uri("http://mock.local/a")
+        .body(Default::default())
+        .unwrap();
+    let err = client.request(req).wait().expect_err("refused");
+    match err.kind() {
+        &::error::Kind::Connect => (),
+        other => panic!("expected Connect, found {:?}", other)
+    }
+
+    let key = (Arc::new("http://mock.local".to_string()), Ver::Http1);
+    let at = client.pool.dial_allowed_at(&key).expect("backing off");
+    assert!(client.pool.connecting(&key).is_none());
+
+    // The next request waits out the backoff before dialing again.
+    let req = Request::builder()
+        .uri("http://mock.local/b")
+        .body(Default::default())
+        .unwrap();
+    client.request(req).wait().expect_err("refused again");
+    assert!(Instant::now() >= at);
+}
+
+#[test]
+fn max_idle_per_host_limits_pooled_connections() {
+    let _ = pretty_env_logger::try_init();
+
+    let executor = ThreadPoolBuilder::new().pool_size(1).build();
+    let mut connector = MockConnector::new();
+
+    let sock1 = connector.mock("http://mock.local");
+    let sock2 = connector.mock("http://mock.local");
+
+    let client = Client::builder()
+        .executor(executor.sender().clone())
+        .max_idle_per_host(Some(1))
+        .build::<_, ::Body>(connector);
+
+    // The first connection stays busy until its body is read, so the
+    // second request dials another.
+    let req = Request::builder()
+        .uri("http://mock.local/a")
+        .body(Default::default())
+        .unwrap();
+    let res1 = client.request(req);
+    let srv1 = poll_fn(|| {
+        try_ready!(sock1.read(&mut [0u8; 512]));
+        try_ready!(sock1.write(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"));
+        Ok(Async::Ready(()))
+    }).map_err(|e: ::std::io::Error| panic!("srv1 poll_fn error: {}", e));
+    let (res1, ()) = res1.join(srv1).wait().expect("res1");
+
+    let req = Request::builder()
+        .uri("http://mock.local/b")
+        .body(Default::default())
+        .unwrap();
+    let res2 = client.request(req);
+    let srv2 = poll_fn(|| {
+        try_ready!(sock2.read(&mut [0u8; 512]));
+        try_ready!(sock2.write(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"));
+        Ok(Async::Ready(()))
+    }).map_err(|e: ::std::io::Error| panic!("srv2 poll_fn error: {}", e));
+    res2.join(srv2).wait().expect("res2");
+
+    let body = res1.into_body().concat2().wait().expect("body1");
+    assert_eq!(&body[..], b"hello");
+
+    // wait for both connections to return to the pool
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while client.pool.outstanding_count() > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(client.pool.outstanding_count(), 0);
+    assert_eq!(client.pool_stats().idle_connections(), 1);
+
+    let stats = client.pool_stats();
+    assert_eq!(stats.idle_connections(), 1);
+    assert_eq!(stats.idle_by_host()["http://mock.local"], 1);
+    assert_eq!(stats.parked_waiters(), 0);
+    assert_eq!(stats.connecting(), 0);
+    assert!(stats.closed_rejections_by_host().is_empty());
+    assert_eq!(stats.dials_in_flight()["http://mock.local"].current(), 0);
+
+    let text = client.pool_metrics_text("hyper_pool");
+    assert!(text.contains("\nhyper_pool_idle_connections 1\n"));
+    assert!(text.contains("hyper_pool_checked_out_connections 0\n"));
+}
+
+#[test]
+fn checkout_too_busy_to_park_waits_on_connect() {
+    let _ = pretty_env_logger::try_init();
+
+    let executor = ThreadPoolBuilder::new().pool_size(1).build();
+    let mut connector = MockConnector::new();
+
+    let sock1 = connector.mock("http://mock.local");
+
+    // No checkout may park, so each one fails as canceled, and the
+    // request has to use its own connect.
+    let client = Client::builder()
+        .executor(executor.sender().clone())
+        .max_parked_per_host(Some(0))
+        .build::<_, ::Body>(connector);
+
+    let req = Request::builder()
+        .uri("http://mock.local/a")
+        .body(Default::default())
+        .unwrap();
+    let res1 = client.request(req)
+        .map(|res| {
+            assert_eq!(res.status().as_u16(), 200);
+        });
+    let srv1 = poll_fn(|| {
+        try_ready!(sock1.read(&mut [0u8; 512]));
+        try_ready!(sock1.write(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"));
+        Ok(Async::Ready(()))
+    }).map_err(|e: ::std::io::Error| panic!("srv1 poll_fn error: {}", e));
+    res1.join(srv1).wait().expect("res1");
+}
+
+/// Counts its dials, which never finish.
+struct PendingConnector(Arc<AtomicUsize>);
+
+impl Connect for PendingConnector {
+    type Transport = ::mock::Duplex;
+    type Error = io::Error;
+    type Future = future::Empty<(Self::Transport, connect::Connected), Self::Error>;
+
+    fn connect(&self, _dst: Destination) -> Self::Future {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        future::empty()
+    }
+}
+
+#[test]
+fn slow_http2_dial_allows_another_after_coalesce_window() {
+    let _ = pretty_env_logger::try_init();
+
+    let executor = ThreadPoolBuilder::new().pool_size(1).build();
+    let dials = Arc::new(AtomicUsize::new(0));
+    let client = Client::builder()
+        .executor(executor.sender().clone())
+        .http2_only(true)
+        .http2_connect_coalesce_window(Some(Duration::from_millis(50)))
+        .build::<_, ::Body>(PendingConnector(dials.clone()));
+
+    let req = || {
+        Request::builder()
+            .uri("http://mock.local/a")
+            .body(Default::default())
+            .unwrap()
+    };
+    let res1 = client.request(req());
+    let res2 = client.request(req());
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let requests = thread::spawn(move || {
+        let _ = res1.join(res2).select2(stop_rx).wait();
+    });
+
+    // The second request waits on the first dial, until the window passes.
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while dials.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(dials.load(Ordering::SeqCst), 2);
+
+    drop(stop_tx);
+    requests.join().unwrap();
+}
+
+#[test]
+fn server_errors_past_threshold_retire_connection() {
+    let _ = pretty_env_logger::try_init();
+
+    let executor = ThreadPoolBuilder::new().pool_size(1).build();
+    let mut connector = MockConnector::new();
+
+    let sock1 = connector.mock("http://mock.local");
+
+    let client = Client::builder()
+        .executor(executor.sender().clone())
+        .pool_error_threshold(Some(1))
+        .build::<_, ::Body>(connector);
+
+    let req = Request::builder()
+        .uri("http://mock.local/a")
+        .body(Default::default())
+        .unwrap();
+    let res1 = client.request(req)
+        .map(|res| {
+            assert_eq!(res.status().as_u16(), 500);
+        });
+    let srv1 = poll_fn(|| {
+        try_ready!(sock1.read(&mut [0u8; 512]));
+        try_ready!(sock1.write(b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"));
+        Ok(Async::Ready(()))
+    }).map_err(|e: ::std::io::Error| panic!("srv1 poll_fn error: {}", e));
+    res1.join(srv1).wait().expect("res1");
+
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while client.pool.outstanding_count() > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(client.pool.outstanding_count(), 0);
+    assert_eq!(client.pool_stats().idle_connections(), 0);
+}
@@ -1,4 +1,5 @@
 use std::fmt::Write;
+use std::time::Duration;
 
 use bytes::BytesMut;
 use http::HeaderMap;
@@ -36,6 +37,25 @@ pub fn connection_close(headers: &HeaderMap) -> bool {
     false
 }
 
+/// The `timeout` parameter of a `Keep-Alive` header, which is how long the
+/// sender keeps an idle connection open.
+pub fn keep_alive_timeout(headers: &HeaderMap) -> Option<Duration> {
+    for line in headers.get_all("keep-alive") {
+        if let Ok(s) = line.to_str() {
+            for param in s.split(',') {
+                let mut parts = param.splitn(2, '=');
+                if let (Some(name), Some(secs)) = (parts.next(), parts.next()) {
+                    if eq_ascii(name.trim(), "timeout") {
+                        return secs.trim().parse().ok().map(Duration::from_secs);
+                    }
+                }
+            }
+        }
+    }
+
+    None
+}
+
 pub fn content_length_parse(headers: &HeaderMap) -> Option<u64> {
     content_length_parse_all(headers.get_all(CONTENT_LENGTH).into_iter())
 }
@@ -144,4 +164,22 @@ mod tests {
             ::std::u64::MAX.to_string().len()
         );
     }
+
+    #[test]
+    fn keep_alive_timeout() {
+        use std::time::Duration;
+        use http::HeaderMap;
+        use http::header::HeaderValue;
+
+        let timeout = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("keep-alive", HeaderValue::from_static(value));
+            super::keep_alive_timeout(&headers)
+        };
+        assert_eq!(timeout("timeout=5, max=100"), Some(Duration::from_secs(5)));
+        assert_eq!(timeout("max=100, Timeout = 7"), Some(Duration::from_secs(7)));
+        assert_eq!(timeout("max=100"), None);
+        assert_eq!(timeout("timeout=soon"), None);
+        assert_eq!(super::keep_alive_timeout(&HeaderMap::new()), None);
+    }
 }